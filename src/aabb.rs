@@ -31,6 +31,23 @@ impl AABB {
         Some(t_range)
     }
 
+    /// Contains nothing; the identity of `merge`.
+    pub fn empty() -> AABB {
+        AABB {
+            min: Vec3::from(f32::INFINITY),
+            max: Vec3::from(-f32::INFINITY),
+        }
+    }
+
+    pub fn centroid(&self) -> Vec3 {
+        0.5 * (self.min + self.max)
+    }
+
+    pub fn surface_area(&self) -> f32 {
+        let d = self.max - self.min;
+        2. * (d[X] * d[Y] + d[Y] * d[Z] + d[Z] * d[X])
+    }
+
     pub fn merge(a: &AABB, b: &AABB) -> AABB {
         AABB {
             max: a.max.max(b.max),
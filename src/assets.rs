@@ -0,0 +1,66 @@
+use crate::texture::{self, Level};
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::Arc;
+
+/// Meshes and images read from files, kept between the frames of an animation so that each
+/// file is read once.
+#[derive(Default)]
+pub struct Assets {
+    models: HashMap<String, Arc<[tobj::Model]>>,
+    images: HashMap<String, Arc<Level>>,
+}
+
+impl Assets {
+    /// Triangulated models of the OBJ file at `path`.
+    pub fn models(&mut self, path: &str) -> Result<Arc<[tobj::Model]>, tobj::LoadError> {
+        if let Some(models) = self.models.get(path) {
+            return Ok(models.clone());
+        }
+
+        let (models, _materials) = tobj::load_obj(path, true)?;
+        let models: Arc<[tobj::Model]> = models.into();
+        self.models.insert(path.to_string(), models.clone());
+
+        Ok(models)
+    }
+
+    /// Pixels of the image at `path` as stored, see `texture::load_image`.
+    pub(crate) fn image(&mut self, path: &str) -> Result<Arc<Level>, Box<dyn Error>> {
+        if let Some(image) = self.images.get(path) {
+            return Ok(image.clone());
+        }
+
+        let image = Arc::new(texture::load_image(path)?);
+        self.images.insert(path.to_string(), image.clone());
+
+        Ok(image)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn files_are_read_once() {
+        let mut assets = Assets::default();
+
+        let path = "./assets/bunny-with-normals.obj";
+        let models = assets.models(path).unwrap();
+        assert!(Arc::ptr_eq(&models, &assets.models(path).unwrap()));
+
+        let path = "./assets/earthmap.jpg";
+        let image = assets.image(path).unwrap();
+        assert!(Arc::ptr_eq(&image, &assets.image(path).unwrap()));
+    }
+
+    #[test]
+    fn failed_reads_are_not_kept() {
+        let mut assets = Assets::default();
+
+        assert!(assets.models("./assets/missing.obj").is_err());
+        assert!(assets.image("./assets/missing.png").is_err());
+        assert!(assets.models.is_empty() && assets.images.is_empty());
+    }
+}
@@ -1,17 +1,95 @@
 use crate::aabb::AABB;
-use crate::math::{Axis3::*, Vec3};
+use crate::light::Light;
+use crate::math::{Axis3, Axis3::*};
 use crate::primitive::{HitRecord, Hittable};
 use crate::ray::Ray;
+use rayon::prelude::*;
+use std::fmt;
 use std::ops::Range;
+use std::time::{Duration, Instant};
 
+/// Bounding volume hierarchy stored as a flat array of nodes in depth-first order.
 pub struct Bvh {
+    nodes: Vec<Node>,
+    /// Ordered so that every leaf refers to a contiguous range.
+    objects: Vec<Box<dyn Hittable>>,
+    /// Collected from all objects.
+    pub(crate) lights: Vec<Light>,
+    stats: BuildStats,
+}
+
+/// Summary of a build, for callers to report.
+#[derive(Debug, Copy, Clone)]
+pub struct BuildStats {
+    pub objects: usize,
+    pub nodes: usize,
+    /// See `Bvh::cost`.
+    pub cost: f32,
+    pub time: Duration,
+}
+
+impl fmt::Display for BuildStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} objects, {} nodes, cost {:.2}, built in {:.2?}",
+            self.objects, self.nodes, self.cost, self.time
+        )
+    }
+}
+
+struct Node {
     bounding_box: AABB,
-    children: BvhChildren,
+    kind: NodeKind,
 }
 
-enum BvhChildren {
-    Node { left: Box<Bvh>, right: Box<Bvh> },
-    Leaf(Box<dyn Hittable>),
+#[derive(Copy, Clone)]
+enum NodeKind {
+    /// The first child directly follows its parent.
+    Interior {
+        second: u32,
+        axis: Axis3,
+    },
+    Leaf {
+        first: u32,
+        count: u32,
+    },
+}
+
+/// Nodes this deep are made leaves, which bounds the traversal stack.
+const MAX_DEPTH: usize = 64;
+
+/// How `Bvh::with_options` partitions objects between the two children of a node.
+#[derive(Debug, Copy, Clone)]
+pub enum Split {
+    /// Halves the objects by their centroids along the longest axis of their bounds.
+    Median,
+    /// Minimizes the surface area heuristic, evaluated at `bins` equally spaced candidate
+    /// planes per axis.
+    Sah { bins: usize },
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct BvhOptions {
+    pub split: Split,
+    /// Nodes with more objects are always split. Smaller nodes become leaves under `Median`,
+    /// and whenever splitting doesn't lower the cost under `Sah`.
+    pub max_leaf_size: usize,
+    /// Relative cost of testing a ray against a node's bounds.
+    pub traversal_cost: f32,
+    /// Relative cost of testing a ray against an object.
+    pub intersection_cost: f32,
+}
+
+impl Default for BvhOptions {
+    fn default() -> Self {
+        BvhOptions {
+            split: Split::Sah { bins: 16 },
+            max_leaf_size: 4,
+            traversal_cost: 1.,
+            intersection_cost: 1.,
+        }
+    }
 }
 
 impl Hittable for Bvh {
@@ -21,120 +99,492 @@ impl Hittable for Bvh {
         mut t_range: Range<f32>,
         rng: &mut dyn FnMut() -> f32,
     ) -> Option<HitRecord> {
-        match self.bounding_box.hit(ray, t_range.clone()) {
-            None => None,
-            Some(t) => match &self.children {
-                BvhChildren::Leaf(obj) => {
-                    obj.hit(ray, t.start.max(t_range.start)..t.end.min(t_range.end), rng)
-                }
-                BvhChildren::Node { left, right } => {
-                    let left_hit = left.hit(ray, t_range.clone(), rng);
+        let mut nearest = None;
+        let mut stack = [0; MAX_DEPTH];
+        let mut stack_len = 0;
+        let mut i = 0;
 
-                    if let Some(h) = &left_hit {
-                        t_range.end = h.t();
-                    }
+        loop {
+            let node = &self.nodes[i];
+
+            // Nodes behind the nearest hit so far are culled by the shrinking range
+            if node.bounding_box.hit(ray, t_range.clone()).is_some() {
+                match node.kind {
+                    NodeKind::Leaf { first, count } => {
+                        let first = first as usize;
 
-                    match (left_hit, right.hit(ray, t_range, rng)) {
-                        (None, None) => None,
-                        (None, o) | (o, None) => o,
-                        (Some(h1), Some(h2)) => {
-                            if h1.t() < h2.t() {
-                                Some(h1)
-                            } else {
-                                Some(h2)
+                        for obj in &self.objects[first..first + count as usize] {
+                            if let Some(h) = obj.hit(ray, t_range.clone(), rng) {
+                                t_range.end = h.t();
+                                nearest = Some(h);
                             }
                         }
                     }
+                    NodeKind::Interior { second, axis } => {
+                        // Visit the child nearer along the split axis first
+                        let (near, far) = if ray.dir[axis] < 0. {
+                            (second as usize, i + 1)
+                        } else {
+                            (i + 1, second as usize)
+                        };
+
+                        stack[stack_len] = far;
+                        stack_len += 1;
+                        i = near;
+                        continue;
+                    }
                 }
-            },
+            }
+
+            if stack_len == 0 {
+                return nearest;
+            }
+
+            stack_len -= 1;
+            i = stack[stack_len];
         }
     }
 
     fn bounding_box(&self, _: Range<f32>) -> AABB {
-        self.bounding_box
+        self.nodes[0].bounding_box
     }
+
+    fn lights(&self) -> Vec<Light> {
+        self.lights.clone()
+    }
+}
+
+type Item = (Box<dyn Hittable>, AABB);
+
+/// Subtrees smaller than this are built on the current thread.
+const PARALLEL_THRESHOLD: usize = 4096;
+
+/// Pointer-based tree produced by the parallel build, flattened afterwards.
+enum BuildNode {
+    Interior {
+        bounding_box: AABB,
+        axis: Axis3,
+        left: Box<BuildNode>,
+        right: Box<BuildNode>,
+    },
+    Leaf {
+        bounding_box: AABB,
+        objects: Vec<Box<dyn Hittable>>,
+    },
 }
 
 impl Bvh {
-    pub fn new(mut objs: Vec<Box<dyn Hittable>>, exposure: Range<f32>) -> Self {
-        let (children, bounding_box) = match objs.len() {
-            0 => panic!("Cannot construct BVH with zero objects."),
-            1 => {
-                let obj = objs.pop().unwrap();
-                let bb = obj.bounding_box(exposure.clone());
-                (BvhChildren::Leaf(obj), bb)
-            }
-            _ => {
-                let (max, min) = objs.iter().fold(
-                    (Vec3::from(-f32::INFINITY), Vec3::from(f32::INFINITY)),
-                    |(max, min), obj| {
-                        let bb = obj.bounding_box(exposure.clone());
+    pub fn new(objs: Vec<Box<dyn Hittable>>, exposure: Range<f32>) -> Self {
+        Bvh::with_options(objs, exposure, &BvhOptions::default())
+    }
 
-                        (max.max(bb.max), min.min(bb.min))
-                    },
-                );
+    pub fn with_options(
+        objs: Vec<Box<dyn Hittable>>,
+        exposure: Range<f32>,
+        options: &BvhOptions,
+    ) -> Self {
+        if objs.is_empty() {
+            panic!("Cannot construct BVH with zero objects.");
+        }
 
-                let max_dim = {
-                    let range = max - min;
+        let start = Instant::now();
+        let count = objs.len();
 
-                    let mut max_dim = X;
-                    let mut max_range = range[X];
+        let lights = objs.par_iter().flat_map_iter(|o| o.lights()).collect();
 
-                    for ax in &[Y, Z] {
-                        if range[*ax] > max_range {
-                            max_dim = *ax;
-                            max_range = range[*ax];
-                        }
+        let items = objs
+            .into_par_iter()
+            .map(|o| {
+                let bb = o.bounding_box(exposure.clone());
+                (o, bb)
+            })
+            .collect();
+
+        let mut bvh = Bvh {
+            nodes: Vec::with_capacity(2 * count),
+            objects: Vec::with_capacity(count),
+            lights,
+            stats: BuildStats {
+                objects: count,
+                nodes: 0,
+                cost: 0.,
+                time: Duration::default(),
+            },
+        };
+        bvh.flatten(Bvh::build(items, 0, options));
+
+        bvh.stats = BuildStats {
+            objects: count,
+            nodes: bvh.nodes.len(),
+            cost: bvh.cost(options),
+            time: start.elapsed(),
+        };
+
+        bvh
+    }
+
+    #[inline]
+    pub fn stats(&self) -> &BuildStats {
+        &self.stats
+    }
+
+    /// Expected cost of tracing a ray that hits the root bounds under the surface area
+    /// heuristic, in the units of `options.traversal_cost` and `options.intersection_cost`.
+    pub fn cost(&self, options: &BvhOptions) -> f32 {
+        let cost = self
+            .nodes
+            .iter()
+            .map(|node| {
+                node.bounding_box.surface_area()
+                    * match node.kind {
+                        NodeKind::Leaf { count, .. } => options.intersection_cost * count as f32,
+                        NodeKind::Interior { .. } => options.traversal_cost,
                     }
+            })
+            .sum::<f32>();
 
-                    max_dim
-                };
+        cost / self.nodes[0].bounding_box.surface_area()
+    }
 
-                objs.sort_unstable_by(|a, b| {
-                    let abb = a.bounding_box(exposure.clone());
-                    let bbb = b.bounding_box(exposure.clone());
+    fn build(mut items: Vec<Item>, depth: usize, options: &BvhOptions) -> BuildNode {
+        let bounding_box = items
+            .iter()
+            .fold(AABB::empty(), |acc, (_, bb)| AABB::merge(&acc, bb));
 
-                    let ac = abb.min[max_dim] + abb.max[max_dim];
-                    let bc = bbb.min[max_dim] + bbb.max[max_dim];
+        let split = match options.split {
+            _ if depth + 1 >= MAX_DEPTH => None,
+            Split::Median if items.len() <= options.max_leaf_size => None,
+            Split::Median => Some(median_split(&mut items)),
+            Split::Sah { bins } => sah_split(&mut items, &bounding_box, bins, options),
+        };
 
-                    ac.partial_cmp(&bc).unwrap()
-                });
+        match split {
+            None => BuildNode::Leaf {
+                bounding_box,
+                objects: items.into_iter().map(|(o, _)| o).collect(),
+            },
+            Some((axis, pivot)) => {
+                let right = items.split_off(pivot);
 
-                let pivot = objs.len() / 2;
+                let (left, right) = if items.len() + right.len() > PARALLEL_THRESHOLD {
+                    rayon::join(
+                        || Bvh::build(items, depth + 1, options),
+                        || Bvh::build(right, depth + 1, options),
+                    )
+                } else {
+                    (
+                        Bvh::build(items, depth + 1, options),
+                        Bvh::build(right, depth + 1, options),
+                    )
+                };
 
-                /*
-                let avg = objs.iter().map(|o| {
-                    let bb = o.bounding_box(exposure.clone());
-                    bb.max[max_dim] + bb.min[max_dim]
-                }).sum::<f32>() / objs.len() as f32;
+                BuildNode::Interior {
+                    bounding_box,
+                    axis,
+                    left: Box::new(left),
+                    right: Box::new(right),
+                }
+            }
+        }
+    }
 
-                let pivot = objs.partition_point(|o| {
-                    let bb = o.bounding_box(exposure.clone());
-                    bb.max[max_dim] + bb.min[max_dim] < avg
+    /// Appends the subtree in depth-first order.
+    fn flatten(&mut self, node: BuildNode) {
+        match node {
+            BuildNode::Leaf {
+                bounding_box,
+                objects,
+            } => {
+                self.nodes.push(Node {
+                    bounding_box,
+                    kind: NodeKind::Leaf {
+                        first: self.objects.len() as u32,
+                        count: objects.len() as u32,
+                    },
+                });
+                self.objects.extend(objects);
+            }
+            BuildNode::Interior {
+                bounding_box,
+                axis,
+                left,
+                right,
+            } => {
+                let index = self.nodes.len();
+                self.nodes.push(Node {
+                    bounding_box,
+                    kind: NodeKind::Interior { second: 0, axis },
                 });
-                */
 
-                let left = Bvh::new(objs.drain(pivot..).collect(), exposure.clone());
-                let right = Bvh::new(objs, exposure.clone());
+                self.flatten(*left);
 
-                let bb = AABB::merge(
-                    &left.bounding_box(exposure.clone()),
-                    &right.bounding_box(exposure.clone()),
-                );
+                let second = self.nodes.len() as u32;
+                self.flatten(*right);
 
-                (
-                    BvhChildren::Node {
-                        left: Box::new(left),
-                        right: Box::new(right),
-                    },
-                    bb,
-                )
+                self.nodes[index].kind = NodeKind::Interior { second, axis };
+            }
+        }
+    }
+}
+
+fn centroid_bounds(items: &[Item]) -> AABB {
+    items.iter().fold(AABB::empty(), |acc, (_, bb)| {
+        let c = bb.centroid();
+        AABB::merge(&acc, &AABB { min: c, max: c })
+    })
+}
+
+fn longest_axis(bb: &AABB) -> Axis3 {
+    let range = bb.max - bb.min;
+
+    let mut max_dim = X;
+
+    for ax in &[Y, Z] {
+        if range[*ax] > range[max_dim] {
+            max_dim = *ax;
+        }
+    }
+
+    max_dim
+}
+
+/// Sorts `items` along the longest axis of their centroids and returns that axis and the
+/// middle index.
+fn median_split(items: &mut [Item]) -> (Axis3, usize) {
+    let axis = longest_axis(&centroid_bounds(items));
+
+    items.sort_unstable_by(|(_, a), (_, b)| {
+        a.centroid()[axis].partial_cmp(&b.centroid()[axis]).unwrap()
+    });
+
+    (axis, items.len() / 2)
+}
+
+/// Partitions `items` at the cheapest binned SAH plane and returns its axis and the index of
+/// the first item on the right, or `None` if a leaf is cheaper.
+fn sah_split(
+    items: &mut [Item],
+    bounding_box: &AABB,
+    bins: usize,
+    options: &BvhOptions,
+) -> Option<(Axis3, usize)> {
+    let n = items.len();
+    let leaf_cost = options.intersection_cost * n as f32;
+
+    if n == 1 {
+        return None;
+    }
+
+    let centroids = centroid_bounds(items);
+    let area = bounding_box.surface_area();
+    let bins = bins.max(2);
+
+    // (cost, axis, first bin on the right)
+    let mut best: Option<(f32, Axis3, usize)> = None;
+
+    for &axis in &[X, Y, Z] {
+        let (lo, hi) = (centroids.min[axis], centroids.max[axis]);
+
+        if hi - lo <= 0. {
+            continue;
+        }
+
+        let mut bin_bounds = vec![AABB::empty(); bins];
+        let mut bin_counts = vec![0; bins];
+
+        for (_, bb) in items.iter() {
+            let b = bin_index(bb.centroid()[axis], lo, hi, bins);
+            bin_bounds[b] = AABB::merge(&bin_bounds[b], bb);
+            bin_counts[b] += 1;
+        }
+
+        // Bounds and counts of everything right of each plane, swept from the right
+        let mut right = vec![(AABB::empty(), 0); bins];
+        let mut acc = (AABB::empty(), 0);
+        for b in (1..bins).rev() {
+            acc = (AABB::merge(&acc.0, &bin_bounds[b]), acc.1 + bin_counts[b]);
+            right[b] = acc;
+        }
+
+        let mut left = (AABB::empty(), 0);
+        for b in 1..bins {
+            left = (
+                AABB::merge(&left.0, &bin_bounds[b - 1]),
+                left.1 + bin_counts[b - 1],
+            );
+            let (right_bb, right_count) = &right[b];
+
+            if left.1 == 0 || *right_count == 0 {
+                continue;
             }
+
+            let cost = options.traversal_cost
+                + options.intersection_cost
+                    * (left.0.surface_area() * left.1 as f32
+                        + right_bb.surface_area() * *right_count as f32)
+                    / area;
+
+            match best {
+                Some((c, _, _)) if c <= cost => {}
+                _ => best = Some((cost, axis, b)),
+            }
+        }
+    }
+
+    match best {
+        Some((cost, axis, bin)) if cost < leaf_cost || n > options.max_leaf_size => {
+            let (lo, hi) = (centroids.min[axis], centroids.max[axis]);
+
+            let mut pivot = 0;
+
+            for i in 0..n {
+                if bin_index(items[i].1.centroid()[axis], lo, hi, bins) < bin {
+                    items.swap(pivot, i);
+                    pivot += 1;
+                }
+            }
+
+            Some((axis, pivot))
+        }
+        // All centroids coincide, so no plane separates the objects
+        None if n > options.max_leaf_size => Some((X, n / 2)),
+        _ => None,
+    }
+}
+
+fn bin_index(c: f32, lo: f32, hi: f32, bins: usize) -> usize {
+    (((c - lo) / (hi - lo) * bins as f32) as usize).min(bins - 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::material::Material;
+    use crate::math::Vec3;
+    use crate::primitive::{Sphere, Triangle};
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    fn sphere(center: Vec3, radius: f32) -> Box<dyn Hittable> {
+        Box::new(Sphere {
+            center,
+            radius,
+            material: Material::Empty,
+        })
+    }
+
+    #[test]
+    fn sah_beats_median_on_skewed_scenes() {
+        let skewed = || {
+            let mut rng = StdRng::seed_from_u64(1);
+            let mut objs: Vec<Box<dyn Hittable>> = (0..200)
+                .map(|_| {
+                    let c = Vec3::new(rng.gen(), rng.gen(), rng.gen());
+                    sphere(c, 0.05)
+                })
+                .collect();
+            objs.push(sphere(Vec3::new(100., 0., 0.), 0.05));
+            objs
+        };
+        let cost = |split| {
+            let options = BvhOptions {
+                split,
+                ..BvhOptions::default()
+            };
+            Bvh::with_options(skewed(), 0.0..1., &options).cost(&options)
         };
 
-        Bvh {
-            children,
-            bounding_box,
+        let (median, sah) = (cost(Split::Median), cost(Split::Sah { bins: 16 }));
+
+        assert!(sah < median, "SAH cost {} >= median cost {}", sah, median);
+    }
+
+    /// Random spheres and triangles in a box 10 units wide, the same for the same `seed`.
+    fn random_scene(seed: u64) -> Vec<Box<dyn Hittable>> {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut point =
+            move || 10. * (Vec3::new(rng.gen(), rng.gen(), rng.gen()) - Vec3::from(0.5));
+
+        (0..150)
+            .map(|i| -> Box<dyn Hittable> {
+                if i % 2 == 0 {
+                    sphere(point(), 0.1 + 0.4 * (point().x() + 5.) / 10.)
+                } else {
+                    let v1 = point();
+                    let vertices = (v1, v1 + 0.3 * point(), v1 + 0.3 * point());
+                    let n = Vec3::cross(vertices.2 - vertices.0, vertices.1 - vertices.0);
+
+                    Box::new(Triangle {
+                        vertices,
+                        normals: (n, n, n),
+                        material: Material::Empty,
+                    })
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn traversal_matches_linear_scan() {
+        let linear = random_scene(2);
+        let mut rng = StdRng::seed_from_u64(3);
+        let rays: Vec<Ray> = (0..2000)
+            .map(|_| {
+                let mut v = || Vec3::new(rng.gen(), rng.gen(), rng.gen()) - Vec3::from(0.5);
+                let origin = 20. * v();
+
+                // Aimed into the scene, so that most rays hit something
+                Ray {
+                    origin,
+                    dir: 10. * v() - origin,
+                    t: 0.,
+                    differential: None,
+                }
+            })
+            .collect();
+
+        let nearest = |ray: &Ray| {
+            linear
+                .iter()
+                .filter_map(|o| o.hit(ray, 0.001..f32::INFINITY, &mut || 0.5))
+                .map(|h| h.t())
+                .fold(None, |acc: Option<f32>, t| {
+                    Some(acc.map_or(t, |a| a.min(t)))
+                })
+        };
+
+        let hits = rays.iter().filter(|r| nearest(r).is_some()).count();
+        assert!(hits > rays.len() / 10, "only {} rays hit", hits);
+
+        for split in [Split::Median, Split::Sah { bins: 16 }] {
+            for max_leaf_size in [1, 8] {
+                let options = BvhOptions {
+                    split,
+                    max_leaf_size,
+                    ..BvhOptions::default()
+                };
+                let bvh = Bvh::with_options(random_scene(2), 0.0..1., &options);
+
+                for ray in &rays {
+                    let hit = bvh.hit(ray, 0.001..f32::INFINITY, &mut || 0.5);
+
+                    match (hit.map(|h| h.t()), nearest(ray)) {
+                        (None, None) => {}
+                        (Some(a), Some(b)) => assert!(
+                            (a - b).abs() <= 1e-5 * b.max(1.),
+                            "{:?}, leaves of {}: {} != {}",
+                            split,
+                            max_leaf_size,
+                            a,
+                            b
+                        ),
+                        (a, b) => panic!(
+                            "{:?}, leaves of {}: {:?} != {:?}",
+                            split, max_leaf_size, a, b
+                        ),
+                    }
+                }
+            }
         }
     }
 }
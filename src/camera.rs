@@ -1,22 +1,315 @@
-use crate::math::{Axis3::*, Vec3};
-use crate::ray::Ray;
+use crate::math::{Axis3::*, Onb, Vec3};
+use crate::ray::{Ray, RayDifferential};
 use rand::Rng;
+use serde::Deserialize;
 
+use std::f32::consts::PI;
 use std::ops::Range;
+use std::sync::Arc;
 
 pub struct Camera {
+    /// Sorted by time, never empty.
+    keys: Vec<CameraKey>,
+    aspect_ratio: f32,
+    /// View of the first key, used as is when there's only one.
+    view: View,
+
+    exposure: Range<f32>,
+    /// Time between reading out the top and the bottom row, zero for a global shutter.
+    readout: f32,
+    projection: Projection,
+    /// Factor the radiance reaching the sensor is multiplied by.
+    exposure_scale: f32,
+    aperture: Aperture,
+    /// How far the lens barrel shifts across the aperture towards the image corners, from
+    /// zero for no vignetting to one for half of the aperture blocked.
+    cat_eye: f32,
+}
+
+/// Shape of the lens opening, which out of focus highlights take on. Scaled by the
+/// aperture diameter of the camera keys.
+#[derive(Clone, Default)]
+pub enum Aperture {
+    #[default]
+    Circle,
+    /// Regular polygon with `blades` corners on the circle, rotated by `rotation` degrees.
+    Polygon { blades: u32, rotation: f32 },
+    /// Grayscale transmission stretched over the square around the circle.
+    Mask(Arc<ApertureMask>),
+}
+
+impl Aperture {
+    /// Point on the aperture relative to a unit circle, with `z` zero.
+    fn sample(&self, rng: &mut impl Rng) -> Vec3 {
+        match self {
+            Aperture::Circle => Vec3::rand_in_unit_disk(rng),
+            Aperture::Polygon { blades, rotation } => {
+                // Uniform on one of the triangles between the center and consecutive corners
+                let blades = (*blades).max(3);
+                let i = rng.gen_range(0..blades);
+                let corner = |i: u32| {
+                    let phi = rotation / 180. * PI + 2. * PI * i as f32 / blades as f32;
+                    Vec3::new(phi.cos(), phi.sin(), 0.)
+                };
+
+                let su = rng.gen::<f32>().sqrt();
+                let v = rng.gen::<f32>();
+                su * (1. - v) * corner(i) + su * v * corner(i + 1)
+            }
+            Aperture::Mask(mask) => mask.sample(rng),
+        }
+    }
+}
+
+/// Image aperture sampled in proportion to its pixel values.
+pub struct ApertureMask {
+    width: usize,
+    height: usize,
+    /// Running sum of the pixel values in row-major order from the top left.
+    cdf: Vec<f32>,
+}
+
+impl ApertureMask {
+    /// Mask from the luminance of `image`, or `None` if it's black everywhere.
+    pub fn new(image: &image::DynamicImage) -> Option<Self> {
+        let luma = image.to_luma16();
+
+        let mut sum = 0.;
+        let cdf = luma
+            .pixels()
+            .map(|p| {
+                sum += p.0[0] as f32 / u16::MAX as f32;
+                sum
+            })
+            .collect();
+
+        if sum <= 0. {
+            return None;
+        }
+
+        Some(ApertureMask {
+            width: luma.width() as usize,
+            height: luma.height() as usize,
+            cdf,
+        })
+    }
+
+    fn sample(&self, rng: &mut impl Rng) -> Vec3 {
+        let total = self.cdf[self.cdf.len() - 1];
+        let u = rng.gen::<f32>() * total;
+        let i = self
+            .cdf
+            .partition_point(|c| *c <= u)
+            .min(self.cdf.len() - 1);
+
+        let x = ((i % self.width) as f32 + rng.gen::<f32>()) / self.width as f32;
+        let y = ((i / self.width) as f32 + rng.gen::<f32>()) / self.height as f32;
+
+        Vec3::new(2. * x - 1., 1. - 2. * y, 0.)
+    }
+}
+
+/// Camera body and lens settings in photographic units, from which the field of view,
+/// aperture and exposure of a `Camera` are derived. Scene radiance is taken to be in cd/m².
+#[derive(Debug, Copy, Clone, Deserialize)]
+#[serde(default)]
+pub struct PhysicalCamera {
+    /// Sensor width and height in millimeters.
+    pub sensor: (f32, f32),
+    /// Focal length in millimeters.
+    pub focal_length: f32,
+    pub f_number: f32,
+    /// Exposure time in seconds.
+    pub shutter_speed: f32,
+    pub iso: f32,
+    /// Length of a meter in scene units.
+    pub units_per_meter: f32,
+}
+
+impl Default for PhysicalCamera {
+    /// A 50mm lens at f/8 on a full frame sensor, exposed for 1/125 s at ISO 100.
+    fn default() -> Self {
+        PhysicalCamera {
+            sensor: (36., 24.),
+            focal_length: 50.,
+            f_number: 8.,
+            shutter_speed: 1. / 125.,
+            iso: 100.,
+            units_per_meter: 1.,
+        }
+    }
+}
+
+impl PhysicalCamera {
+    /// Vertical field of view in degrees of an image with the given aspect ratio, cropped
+    /// from the sensor so that it fills the sensor in at least one dimension.
+    pub fn vfov(&self, aspect_ratio: f32) -> f32 {
+        let (width, height) = self.sensor;
+        let height = height.min(width / aspect_ratio);
+
+        2. * (height / (2. * self.focal_length)).atan() * 180. / PI
+    }
+
+    /// Diameter of the entrance pupil in scene units.
+    pub fn aperture(&self) -> f32 {
+        self.focal_length / self.f_number / 1000. * self.units_per_meter
+    }
+
+    /// Exposure interval starting at `open`.
+    pub fn exposure(&self, open: f32) -> Range<f32> {
+        open..open + self.shutter_speed
+    }
+
+    /// Scale from scene radiance to sensor values, where 1 is the saturation of a sensor
+    /// with the ISO 12232 saturation based speed: `t * S / (1.2 * 100 * N²)`.
+    pub fn exposure_scale(&self) -> f32 {
+        self.shutter_speed * self.iso / (120. * self.f_number * self.f_number)
+    }
+}
+
+/// How points on the image are mapped to rays. Directions are relative to the camera frame,
+/// looking from `lookfrom` towards `lookat` with `vup` up.
+#[derive(Debug, Copy, Clone, Default, Deserialize)]
+pub enum Projection {
+    /// Thin lens perspective with the vertical field of view of the camera keys.
+    #[default]
+    Perspective,
+    /// Parallel rays from a view plane `height` units tall.
+    Orthographic { height: f32 },
+    /// Circular fisheye covering `fov` degrees across the circle inscribed in the image.
+    /// Points outside the circle get no ray.
+    Fisheye { mapping: FisheyeMapping, fov: f32 },
+    /// Longitude across and latitude up the image, covering the full sphere at 2:1.
+    Equirectangular,
+    /// The six faces +X, -X, +Y, -Y, +Z, -Z of a cube side by side at 6:1, with X right,
+    /// Y up and Z backwards, each oriented like an OpenGL cube map face.
+    Cubemap,
+}
+
+#[derive(Debug, Copy, Clone, Deserialize)]
+pub enum FisheyeMapping {
+    /// Distance from the center proportional to the angle off axis.
+    Equidistant,
+    /// Distance from the center proportional to the sine of half the angle off axis, which
+    /// preserves solid angle.
+    Equisolid,
+}
+
+/// Camera placement and lens at a point in time.
+#[derive(Debug, Copy, Clone)]
+pub struct CameraKey {
+    pub time: f32,
+    pub lookfrom: Vec3,
+    pub lookat: Vec3,
+    pub vup: Vec3,
+    /// Vertical field of view in degrees.
+    pub vfov: f32,
+    pub focus_distance: f32,
+    pub aperture: f32,
+}
+
+/// Viewport on the focus plane derived from a `CameraKey`.
+#[derive(Copy, Clone)]
+struct View {
     origin: Vec3,
     lower_left: Vec3,
     horizontal: Vec3,
     vertical: Vec3,
     lens_radius: f32,
-
-    exposure: Range<f32>,
-
-    #[allow(unused)]
-    w: Vec3,
+    focus_distance: f32,
     u: Vec3,
     v: Vec3,
+    w: Vec3,
+}
+
+impl View {
+    fn new(key: &CameraKey, aspect_ratio: f32) -> Self {
+        let fov_rad = key.vfov / 180.0 * std::f32::consts::PI;
+        let h = (fov_rad / 2.0).tan();
+        let viewport_height = 2.0 * h;
+        let viewport_width = aspect_ratio * viewport_height;
+
+        let w = (key.lookfrom - key.lookat).unit();
+        let u = Vec3::cross(key.vup, w).unit();
+        let v = Vec3::cross(w, u);
+
+        let origin = key.lookfrom;
+        let horizontal = key.focus_distance * viewport_width * u;
+        let vertical = key.focus_distance * viewport_height * v;
+        let lower_left = origin - (vertical + horizontal) / 2.0 - key.focus_distance * w;
+
+        View {
+            origin,
+            horizontal,
+            vertical,
+            lower_left,
+            lens_radius: key.aperture / 2.0,
+            focus_distance: key.focus_distance,
+            u,
+            v,
+            w,
+        }
+    }
+
+    /// Origin and unit direction of the pinhole ray through `(s, t)` for the projections
+    /// other than perspective.
+    fn project(&self, projection: Projection, s: f32, t: f32, aspect_ratio: f32) -> Option<Ray> {
+        // Camera frame coordinates, with z pointing backwards
+        let frame = |d: Vec3| d[X] * self.u + d[Y] * self.v + d[Z] * self.w;
+
+        let (origin, dir) = match projection {
+            Projection::Perspective => unreachable!(),
+            Projection::Orthographic { height } => {
+                let offset = Vec3::new((s - 0.5) * aspect_ratio, t - 0.5, 0.) * height;
+                (self.origin + frame(offset), -self.w)
+            }
+            Projection::Fisheye { mapping, fov } => {
+                let (x, y) = ((2. * s - 1.) * aspect_ratio, 2. * t - 1.);
+                let r = (x * x + y * y).sqrt();
+
+                if r > 1. {
+                    return None;
+                }
+
+                let half_fov = fov / 360. * PI;
+                let theta = match mapping {
+                    FisheyeMapping::Equidistant => r * half_fov,
+                    FisheyeMapping::Equisolid => 2. * (r * (half_fov / 2.).sin()).min(1.).asin(),
+                };
+                let (sin_phi, cos_phi) = if r > 0. { (y / r, x / r) } else { (0., 1.) };
+
+                let d = Vec3::new(theta.sin() * cos_phi, theta.sin() * sin_phi, -theta.cos());
+                (self.origin, frame(d))
+            }
+            Projection::Equirectangular => {
+                let (lon, lat) = ((s - 0.5) * 2. * PI, (t - 0.5) * PI);
+                let d = Vec3::new(lat.cos() * lon.sin(), lat.sin(), -lat.cos() * lon.cos());
+                (self.origin, frame(d))
+            }
+            Projection::Cubemap => {
+                let face = ((s * 6.) as i32).clamp(0, 5);
+                let sc = 2. * (s * 6. - face as f32) - 1.;
+                let tc = 1. - 2. * t;
+
+                let d = match face {
+                    0 => Vec3::new(1., -tc, -sc),
+                    1 => Vec3::new(-1., -tc, sc),
+                    2 => Vec3::new(sc, 1., tc),
+                    3 => Vec3::new(sc, -1., -tc),
+                    4 => Vec3::new(sc, -tc, 1.),
+                    _ => Vec3::new(-sc, -tc, -1.),
+                };
+                (self.origin, frame(d).unit())
+            }
+        };
+
+        Some(Ray {
+            origin,
+            dir,
+            t: 0.,
+            differential: None,
+        })
+    }
 }
 
 impl Camera {
@@ -30,40 +323,321 @@ impl Camera {
         aperture: f32,
         exposure: Range<f32>,
     ) -> Self {
-        let fov_rad = vfov / 180.0 * std::f32::consts::PI;
-        let h = (fov_rad / 2.0).tan();
-        let viewport_height = 2.0 * h;
-        let viewport_width = aspect_ratio * viewport_height;
+        let key = CameraKey {
+            time: exposure.start,
+            lookfrom,
+            lookat,
+            vup,
+            vfov,
+            focus_distance: focal_length,
+            aperture,
+        };
 
-        let w = (lookfrom - lookat).unit();
-        let u = Vec3::cross(vup, w).unit();
-        let v = Vec3::cross(w, u);
+        Camera::animated(vec![key], aspect_ratio, exposure)
+    }
+
+    /// Camera whose placement and lens are interpolated linearly between `keys` by the time
+    /// of each ray, so that camera motion blurs within the exposure.
+    pub fn animated(mut keys: Vec<CameraKey>, aspect_ratio: f32, exposure: Range<f32>) -> Self {
+        assert!(!keys.is_empty(), "Camera needs a key.");
 
-        let origin = lookfrom;
-        let horizontal = focal_length * viewport_width * u;
-        let vertical = focal_length * viewport_height * v;
-        let lower_left = origin - (vertical + horizontal) / 2.0 - focal_length * w;
+        keys.sort_by(|a, b| a.time.partial_cmp(&b.time).unwrap());
 
         Camera {
-            origin,
-            horizontal,
-            vertical,
-            lower_left,
-            lens_radius: aperture / 2.0,
+            view: View::new(&keys[0], aspect_ratio),
+            keys,
+            aspect_ratio,
             exposure,
-            w,
-            u,
-            v,
+            readout: 0.,
+            projection: Projection::Perspective,
+            exposure_scale: 1.,
+            aperture: Aperture::Circle,
+            cat_eye: 0.,
+        }
+    }
+
+    pub fn with_aperture(self, aperture: Aperture) -> Self {
+        Camera { aperture, ..self }
+    }
+
+    /// Simulates optical vignetting, where the lens barrel cuts off part of the aperture
+    /// off axis. Out of focus highlights turn into cat's eyes towards the image edges, and
+    /// the blocked rays darken them. `strength` is clamped to [0, 1].
+    pub fn with_cat_eye(self, strength: f32) -> Self {
+        Camera {
+            cat_eye: strength.clamp(0., 1.),
+            ..self
+        }
+    }
+
+    pub fn with_exposure_scale(self, exposure_scale: f32) -> Self {
+        Camera {
+            exposure_scale,
+            ..self
+        }
+    }
+
+    #[inline]
+    pub fn exposure(&self) -> Range<f32> {
+        self.exposure.clone()
+    }
+
+    #[inline]
+    pub fn exposure_scale(&self) -> f32 {
+        self.exposure_scale
+    }
+
+    pub fn with_projection(self, projection: Projection) -> Self {
+        Camera { projection, ..self }
+    }
+
+    /// Simulates a rolling shutter that reads the image out row by row from the top, taking
+    /// `readout` in total. The exposure interval covers the whole capture, so each row is
+    /// exposed for its length minus `readout`, starting `readout` later at the bottom than
+    /// at the top. Objects moving during the readout appear skewed.
+    pub fn with_rolling_shutter(self, readout: f32) -> Self {
+        let len = self.exposure.end - self.exposure.start;
+
+        Camera {
+            readout: readout.max(0.).min(len),
+            ..self
+        }
+    }
+
+    fn view_at(&self, time: f32) -> View {
+        if self.keys.len() == 1 {
+            return self.view;
+        }
+
+        match self.keys.iter().position(|k| k.time > time) {
+            Some(0) => self.view,
+            None => View::new(&self.keys[self.keys.len() - 1], self.aspect_ratio),
+            Some(i) => {
+                let (a, b) = (&self.keys[i - 1], &self.keys[i]);
+                let t = (time - a.time) / (b.time - a.time);
+                let lerp = |a: Vec3, b: Vec3| (1. - t) * a + t * b;
+
+                let key = CameraKey {
+                    time,
+                    lookfrom: lerp(a.lookfrom, b.lookfrom),
+                    lookat: lerp(a.lookat, b.lookat),
+                    vup: lerp(a.vup, b.vup),
+                    vfov: (1. - t) * a.vfov + t * b.vfov,
+                    focus_distance: (1. - t) * a.focus_distance + t * b.focus_distance,
+                    aperture: (1. - t) * a.aperture + t * b.aperture,
+                };
+
+                View::new(&key, self.aspect_ratio)
+            }
+        }
+    }
+
+    /// Ray through the point `(s, t)` of the image, where `(0, 0)` is the bottom left and
+    /// `(1, 1)` the top right corner, or `None` if the projection doesn't cover the point.
+    pub fn get_ray(&self, s: f32, t: f32, rng: &mut impl Rng) -> Option<Ray> {
+        let (time, view, lens) = self.sample_lens(s, t, rng)?;
+
+        self.ray_through(&view, s, t, lens, time)
+    }
+
+    /// Like `get_ray`, with differentials through the points offset by `pixel`, the size of
+    /// a pixel in image coordinates. They share the time and lens position of the ray.
+    pub fn get_ray_differential(
+        &self,
+        s: f32,
+        t: f32,
+        pixel: (f32, f32),
+        rng: &mut impl Rng,
+    ) -> Option<Ray> {
+        let (time, view, lens) = self.sample_lens(s, t, rng)?;
+        let ray = self.ray_through(&view, s, t, lens, time)?;
+
+        let rx = self.ray_through(&view, s + pixel.0, t, lens, time);
+        let ry = self.ray_through(&view, s, t + pixel.1, lens, time);
+
+        Some(Ray {
+            differential: rx.zip(ry).map(|(rx, ry)| RayDifferential {
+                rx_origin: rx.origin,
+                rx_dir: rx.dir,
+                ry_origin: ry.origin,
+                ry_dir: ry.dir,
+            }),
+            ..ray
+        })
+    }
+
+    /// Time, view and point on the unit lens for a ray through `(s, t)`, or `None` if the
+    /// lens point is vignetted.
+    fn sample_lens(&self, s: f32, t: f32, rng: &mut impl Rng) -> Option<(f32, View, Vec3)> {
+        let time = if self.exposure.is_empty() {
+            self.exposure.start
+        } else if self.readout > 0. {
+            let row_start = self.exposure.start + (1. - t).clamp(0., 1.) * self.readout;
+            let row_exposure = self.exposure.end - self.exposure.start - self.readout;
+
+            row_start + rng.gen::<f32>() * row_exposure
+        } else {
+            rng.gen_range(self.exposure.clone())
+        };
+        let view = self.view_at(time);
+
+        let lens = self.aperture.sample(rng);
+
+        if self.cat_eye > 0. {
+            // The barrel's opening, shifted away from the image center and normalized so that
+            // it's shifted by `cat_eye` at the corners
+            let p = Vec3::new((2. * s - 1.) * self.aspect_ratio, 2. * t - 1., 0.)
+                / (1. + self.aspect_ratio * self.aspect_ratio).sqrt();
+
+            if (lens - self.cat_eye * p).len() > 1. {
+                return None;
+            }
         }
+
+        Some((time, view, lens))
     }
 
-    pub fn get_ray(&self, s: f32, t: f32, rng: &mut impl Rng) -> Ray {
-        let rd = self.lens_radius * Vec3::rand_in_unit_disk(rng);
-        let offset = self.u * rd[X] + self.v * rd[Y];
-        Ray {
-            origin: self.origin + offset,
-            dir: self.lower_left + s * self.horizontal + t * self.vertical - self.origin - offset,
-            t: rng.gen_range(self.exposure.clone()),
+    fn ray_through(&self, view: &View, s: f32, t: f32, lens: Vec3, time: f32) -> Option<Ray> {
+        let rd = view.lens_radius * lens;
+
+        if let Projection::Perspective = self.projection {
+            let offset = view.u * rd[X] + view.v * rd[Y];
+
+            return Some(Ray {
+                origin: view.origin + offset,
+                dir: view.lower_left + s * view.horizontal + t * view.vertical
+                    - view.origin
+                    - offset,
+                t: time,
+                differential: None,
+            });
         }
+
+        let pinhole = view.project(self.projection, s, t, self.aspect_ratio)?;
+
+        // Focus at `focus_distance` along the ray, with the lens perpendicular to it
+        let lens = Onb::from_w(pinhole.dir);
+        let offset = lens.u() * rd[X] + lens.v() * rd[Y];
+        let focus = pinhole.origin + view.focus_distance * pinhole.dir;
+
+        Some(Ray {
+            origin: pinhole.origin + offset,
+            dir: focus - pinhole.origin - offset,
+            t: time,
+            differential: None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    fn assert_close(a: Vec3, b: Vec3) {
+        assert!((a - b).len() < 1e-4, "{:?} != {:?}", a, b);
+    }
+
+    /// Camera at the origin looking down +X, so that its right is +Z and its up +Y.
+    fn camera(projection: Projection, aspect_ratio: f32) -> Camera {
+        Camera::new(
+            Vec3::from(0.),
+            Vec3::new(1., 0., 0.),
+            Vec3::new(0., 1., 0.),
+            90.,
+            aspect_ratio,
+            1.,
+            0.,
+            0.0..0.0,
+        )
+        .with_projection(projection)
+    }
+
+    fn direction(camera: &Camera, s: f32, t: f32) -> Vec3 {
+        let mut rng = StdRng::seed_from_u64(0);
+        camera.get_ray(s, t, &mut rng).unwrap().dir.unit()
+    }
+
+    fn forward() -> Vec3 {
+        Vec3::new(1., 0., 0.)
+    }
+
+    fn right() -> Vec3 {
+        Vec3::new(0., 0., 1.)
+    }
+
+    fn up() -> Vec3 {
+        Vec3::new(0., 1., 0.)
+    }
+
+    #[test]
+    fn fisheye_center_looks_forward() {
+        for &mapping in &[FisheyeMapping::Equidistant, FisheyeMapping::Equisolid] {
+            let camera = camera(Projection::Fisheye { mapping, fov: 180. }, 1.);
+
+            assert_close(direction(&camera, 0.5, 0.5), forward());
+            // The edge of a 180° circle looks sideways
+            assert_close(direction(&camera, 1., 0.5), right());
+            assert_close(direction(&camera, 0.5, 1.), up());
+
+            let mut rng = StdRng::seed_from_u64(0);
+            assert!(camera.get_ray(1., 1., &mut rng).is_none());
+        }
+    }
+
+    #[test]
+    fn equirectangular_center_looks_forward() {
+        let camera = camera(Projection::Equirectangular, 2.);
+
+        assert_close(direction(&camera, 0.5, 0.5), forward());
+        assert_close(direction(&camera, 0.75, 0.5), right());
+        assert_close(direction(&camera, 0.25, 0.5), -right());
+        assert_close(direction(&camera, 0., 0.5), -forward());
+        assert_close(direction(&camera, 0.5, 1.), up());
+    }
+
+    #[test]
+    fn cubemap_faces_look_along_their_axes() {
+        let camera = camera(Projection::Cubemap, 6.);
+        // +X, -X, +Y, -Y, +Z, -Z in the camera frame, where Z points backwards
+        let axes = [right(), -right(), up(), -up(), -forward(), forward()];
+
+        for (face, &axis) in axes.iter().enumerate() {
+            let s = (face as f32 + 0.5) / 6.;
+            assert_close(direction(&camera, s, 0.5), axis);
+        }
+    }
+
+    #[test]
+    fn physical_exposure_scale() {
+        // Sunny 16 rule: f/16 at 1/100 s for ISO 100
+        let camera = PhysicalCamera {
+            f_number: 16.,
+            shutter_speed: 0.01,
+            iso: 100.,
+            ..PhysicalCamera::default()
+        };
+        let expected = 0.01 * 100. / (120. * 16. * 16.);
+
+        assert!((camera.exposure_scale() - expected).abs() < 1e-9);
+        assert_eq!(camera.exposure(1.), 1.0..1.01);
+    }
+
+    #[test]
+    fn physical_field_of_view() {
+        let camera = PhysicalCamera::default();
+        let degrees = |rad: f32| rad * 180. / PI;
+
+        // 3:2 and narrower images use the full 24mm sensor height
+        let full = degrees(2. * (12f32 / 50.).atan());
+        assert!((camera.vfov(1.5) - full).abs() < 1e-4);
+        assert!((camera.vfov(1.) - full).abs() < 1e-4);
+
+        // Wider images are cropped to the 36mm sensor width
+        let cropped = degrees(2. * (6f32 / 50.).atan());
+        assert!((camera.vfov(3.) - cropped).abs() < 1e-4);
+
+        assert!((camera.aperture() - 0.006_25).abs() < 1e-7);
     }
 }
@@ -0,0 +1,157 @@
+use crate::math::{Channel::*, Vec3, ZipMap};
+use serde::Deserialize;
+
+/// Encoding of the values stored in an image texture.
+#[derive(Debug, Copy, Clone, PartialEq, Default, Deserialize)]
+pub enum ColorSpace {
+    /// sRGB transfer function and Rec. 709 primaries, as used by most 8-bit color images.
+    #[default]
+    Srgb,
+    /// Linear Rec. 709.
+    Linear,
+    /// Data such as normals or roughness, used as stored.
+    Raw,
+}
+
+impl ColorSpace {
+    /// Converts the stored value `c` into linear `working` space values.
+    pub fn decode(self, c: Vec3, working: WorkingSpace) -> Vec3 {
+        match self {
+            ColorSpace::Srgb => working.from_rec709(c.map(srgb_decode)),
+            ColorSpace::Linear => working.from_rec709(c),
+            ColorSpace::Raw => c,
+        }
+    }
+}
+
+/// Linear color space that rendering happens in. Colors given as numbers are taken to be in
+/// the working space, images are converted into it and output is converted back to Rec. 709.
+#[derive(Debug, Copy, Clone, PartialEq, Default, Deserialize)]
+pub enum WorkingSpace {
+    /// The primaries of sRGB.
+    #[default]
+    Rec709,
+    /// The AP1 primaries of ACES, whose wider gamut makes multiplied colors such as
+    /// interreflections behave more like spectral rendering.
+    AcesCg,
+}
+
+impl WorkingSpace {
+    pub const ALL: [WorkingSpace; 2] = [WorkingSpace::Rec709, WorkingSpace::AcesCg];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            WorkingSpace::Rec709 => "rec709",
+            WorkingSpace::AcesCg => "acescg",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<WorkingSpace> {
+        WorkingSpace::ALL.iter().copied().find(|w| w.name() == name)
+    }
+
+    pub fn from_rec709(self, c: Vec3) -> Vec3 {
+        match self {
+            WorkingSpace::Rec709 => c,
+            WorkingSpace::AcesCg => mat3_mul(&REC709_TO_ACESCG, c),
+        }
+    }
+
+    pub fn to_rec709(self, c: Vec3) -> Vec3 {
+        match self {
+            WorkingSpace::Rec709 => c,
+            WorkingSpace::AcesCg => mat3_mul(&ACESCG_TO_REC709, c),
+        }
+    }
+}
+
+/// Includes the Bradford adaptation from the D65 to the D60 white point.
+const REC709_TO_ACESCG: [[f32; 3]; 3] = [
+    [0.613_097_4, 0.339_523_15, 0.047_379_45],
+    [0.070_193_72, 0.916_353_9, 0.013_452_4],
+    [0.020_615_593, 0.109_569_77, 0.869_814_6],
+];
+
+const ACESCG_TO_REC709: [[f32; 3]; 3] = [
+    [1.705_051, -0.621_792_1, -0.083_258_87],
+    [-0.130_256_42, 1.140_804_7, -0.010_548_32],
+    [-0.024_003_357, -0.128_968_98, 1.152_972_3],
+];
+
+/// sRGB transfer function for a linear value in [0, 1].
+pub fn srgb_encode(v: f32) -> f32 {
+    if v <= 0.003_130_8 {
+        12.92 * v
+    } else {
+        1.055 * v.powf(1. / 2.4) - 0.055
+    }
+}
+
+/// Inverse of `srgb_encode`.
+pub fn srgb_decode(v: f32) -> f32 {
+    if v <= 0.040_45 {
+        v / 12.92
+    } else {
+        ((v + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+/// Row-major 3×3 matrix times `c`.
+pub(crate) fn mat3_mul(m: &[[f32; 3]; 3], c: Vec3) -> Vec3 {
+    let row = |r: &[f32; 3]| r[0] * c[R] + r[1] * c[G] + r[2] * c[B];
+    Vec3::new(row(&m[0]), row(&m[1]), row(&m[2]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(a: Vec3, b: Vec3) {
+        assert!((a - b).len() < 1e-4, "{:?} != {:?}", a, b);
+    }
+
+    #[test]
+    fn acescg_round_trips() {
+        for &c in &[
+            Vec3::new(1., 0., 0.),
+            Vec3::new(0., 1., 0.),
+            Vec3::new(0., 0., 1.),
+            Vec3::new(0.2, 0.5, 3.),
+        ] {
+            let aces = WorkingSpace::AcesCg;
+            assert_close(aces.to_rec709(aces.from_rec709(c)), c);
+            assert_close(aces.from_rec709(aces.to_rec709(c)), c);
+        }
+    }
+
+    #[test]
+    fn acescg_keeps_white() {
+        // Bradford adaptation maps D65 white to D60 white, which is (1, 1, 1) in both spaces
+        assert_close(
+            WorkingSpace::AcesCg.from_rec709(Vec3::from(1.)),
+            Vec3::from(1.),
+        );
+    }
+
+    #[test]
+    fn srgb_round_trips() {
+        for i in 0..=100 {
+            let v = i as f32 / 100.;
+            assert!((srgb_decode(srgb_encode(v)) - v).abs() < 1e-5, "{}", v);
+            assert!((srgb_encode(srgb_decode(v)) - v).abs() < 1e-5, "{}", v);
+        }
+    }
+
+    #[test]
+    fn srgb_knee_is_continuous() {
+        let knee = 0.003_130_8;
+        let below = srgb_encode(knee);
+        let above = srgb_encode(knee + 1e-6);
+
+        assert!((below - 0.040_45).abs() < 1e-4, "{}", below);
+        assert!((above - below).abs() < 1e-4, "{} {}", below, above);
+        assert!((srgb_decode(below) - knee).abs() < 1e-6);
+        assert_eq!(srgb_encode(0.), 0.);
+        assert!((srgb_encode(1.) - 1.).abs() < 1e-6);
+    }
+}
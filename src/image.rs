@@ -1,14 +1,291 @@
 use crate::camera::Camera;
-use crate::math::{Channel::*, Vec3};
-use crate::world::{ray_color, Background, World};
+use crate::color::WorkingSpace;
+use crate::math::{Channel::*, Vec3, ZipMap};
+use crate::tonemap::ToneMap;
+use crate::world::{ray_color, trace_path, Background, FirstHit, PathConfig, World};
 
-use rand::{thread_rng, Rng};
+use ::image::codecs::hdr::HdrEncoder;
+use ::image::{ImageError, ImageFormat, Rgb, RgbImage};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
 use rayon::prelude::*;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
 use std::sync::Mutex;
 
 pub struct Image(Vec<Vec<Vec3>>);
 
+/// Auxiliary render passes taken from the first intersection of each camera ray.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Pass {
+    Albedo,
+    Normal,
+    Depth,
+    Position,
+    Uv,
+    Id,
+    MaterialId,
+}
+
+impl Pass {
+    pub const ALL: [Pass; 7] = [
+        Pass::Albedo,
+        Pass::Normal,
+        Pass::Depth,
+        Pass::Position,
+        Pass::Uv,
+        Pass::Id,
+        Pass::MaterialId,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Pass::Albedo => "albedo",
+            Pass::Normal => "normal",
+            Pass::Depth => "depth",
+            Pass::Position => "position",
+            Pass::Uv => "uv",
+            Pass::Id => "id",
+            Pass::MaterialId => "material-id",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Pass> {
+        Pass::ALL.iter().copied().find(|p| p.name() == name)
+    }
+
+    fn channels(self) -> &'static [&'static str] {
+        match self {
+            Pass::Albedo | Pass::Normal | Pass::Position => &["R", "G", "B"],
+            Pass::Uv => &["U", "V"],
+            Pass::Depth => &["Z"],
+            Pass::Id | Pass::MaterialId => &["id"],
+        }
+    }
+
+    fn value(self, hit: &FirstHit) -> Vec3 {
+        match self {
+            Pass::Albedo => hit.albedo,
+            Pass::Normal => hit.normal,
+            Pass::Depth => Vec3::from(hit.depth),
+            Pass::Position => hit.position,
+            Pass::Uv => hit.uv,
+            Pass::Id => Vec3::from(hit.id as f32),
+            Pass::MaterialId => Vec3::from(hit.material_id as f32),
+        }
+    }
+
+    /// Maps the raw pass values into [0, 1] for 8-bit formats.
+    fn visualize(self, img: &Image) -> Image {
+        let finite_max = |axis: fn(&Vec3) -> f32| {
+            img.0
+                .iter()
+                .flatten()
+                .map(axis)
+                .filter(|v| v.is_finite())
+                .reduce(f32::max)
+                .unwrap_or(0.)
+        };
+        let finite_min = |axis: fn(&Vec3) -> f32| {
+            img.0
+                .iter()
+                .flatten()
+                .map(axis)
+                .filter(|v| v.is_finite())
+                .reduce(f32::min)
+                .unwrap_or(0.)
+        };
+
+        match self {
+            Pass::Albedo | Pass::Uv => img.map(|c| c),
+            Pass::Normal => img.map(|c| 0.5 * (c + Vec3::from(1.))),
+            Pass::Depth => {
+                let max = finite_max(Vec3::x).max(f32::EPSILON);
+                img.map(|c| Vec3::from((c[R] / max).min(1.)))
+            }
+            Pass::Position => {
+                let min = Vec3::new(
+                    finite_min(Vec3::x),
+                    finite_min(Vec3::y),
+                    finite_min(Vec3::z),
+                );
+                let max = Vec3::new(
+                    finite_max(Vec3::x),
+                    finite_max(Vec3::y),
+                    finite_max(Vec3::z),
+                );
+                let inv_range = (max - min).max(Vec3::from(f32::EPSILON)).map(|v| 1. / v);
+                img.map(|c| (c - min) * inv_range)
+            }
+            Pass::Id | Pass::MaterialId => img.map(|c| {
+                let id = c[R] as u32;
+                if id == 0 {
+                    return Vec3::from(0.);
+                }
+                // Spread consecutive IDs to visibly distinct colors
+                let h = id.wrapping_mul(0x9E37_79B9);
+                Vec3::new(
+                    (h >> 24) as f32 / 255.,
+                    ((h >> 16) & 0xff) as f32 / 255.,
+                    ((h >> 8) & 0xff) as f32 / 255.,
+                )
+            }),
+        }
+    }
+}
+
+/// Beauty image together with the requested auxiliary passes.
+pub struct Layers {
+    pub beauty: Image,
+    pub passes: Vec<(Pass, Image)>,
+}
+
+impl Layers {
+    /// OpenEXR output stores all passes as layers of a single file. Other formats write
+    /// each pass next to the beauty image as `<name>.<pass>.<ext>`.
+    /// The beauty image is written last, so that its presence means the passes are complete.
+    /// Only the 8-bit beauty image is tone mapped, but the beauty and albedo are converted
+    /// from the working space of `tone_map` in all formats.
+    pub fn save(&self, path: impl AsRef<Path>, tone_map: &ToneMap) -> Result<(), SaveError> {
+        let path = path.as_ref();
+
+        if extension(path) == "exr" {
+            return self.save_exr(path, tone_map.working_space);
+        }
+
+        let albedo = ToneMap {
+            working_space: tone_map.working_space,
+            ..ToneMap::default()
+        };
+
+        for (pass, img) in &self.passes {
+            let mut name = path.file_stem().unwrap_or_default().to_os_string();
+            name.push(".");
+            name.push(pass.name());
+            if let Some(ext) = path.extension() {
+                name.push(".");
+                name.push(ext);
+            }
+            let pass_path: PathBuf = path.with_file_name(name);
+
+            match extension(path).as_str() {
+                "hdr" if *pass == Pass::Albedo => img.save(&pass_path, &albedo)?,
+                "hdr" => img.save(&pass_path, &ToneMap::default())?,
+                _ if *pass == Pass::Albedo => pass.visualize(img).save(&pass_path, &albedo)?,
+                _ => pass.visualize(img).save_linear(&pass_path)?,
+            }
+        }
+
+        self.beauty.save(path, tone_map)
+    }
+
+    fn save_exr(&self, path: &Path, working_space: WorkingSpace) -> Result<(), SaveError> {
+        use exr::prelude::{AnyChannel, AnyChannels, FlatSamples, WritableImage};
+
+        let axes: [fn(&Vec3) -> f32; 3] = [Vec3::x, Vec3::y, Vec3::z];
+        let channel = |name: &str, img: &Image, axis: fn(&Vec3) -> f32| {
+            AnyChannel::new(
+                name,
+                FlatSamples::F32(img.0.iter().flatten().map(axis).collect()),
+            )
+        };
+
+        let to_rec709 = |img: &Image| img.map(|c| working_space.to_rec709(c));
+
+        let mut channels = vec![];
+        let beauty = to_rec709(&self.beauty);
+        for (name, axis) in ["R", "G", "B"].iter().zip(&axes) {
+            channels.push(channel(name, &beauty, *axis));
+        }
+        for (pass, img) in &self.passes {
+            let converted;
+            let img = if *pass == Pass::Albedo {
+                converted = to_rec709(img);
+                &converted
+            } else {
+                img
+            };
+
+            for (name, axis) in pass.channels().iter().zip(&axes) {
+                channels.push(channel(&format!("{}.{}", pass.name(), name), img, *axis));
+            }
+        }
+
+        exr::prelude::Image::from_channels(
+            (self.beauty.width(), self.beauty.height()),
+            AnyChannels::sort(channels.into()),
+        )
+        .write()
+        .to_file(partial_path(path))?;
+
+        fs::rename(partial_path(path), path)?;
+
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub enum SaveError {
+    UnsupportedFormat(String),
+    Io(io::Error),
+    Image(ImageError),
+    Exr(exr::error::Error),
+}
+
+impl fmt::Display for SaveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SaveError::UnsupportedFormat(ext) => write!(f, "unsupported image format \"{}\"", ext),
+            SaveError::Io(e) => write!(f, "{}", e),
+            SaveError::Image(e) => write!(f, "{}", e),
+            SaveError::Exr(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for SaveError {}
+
+impl From<io::Error> for SaveError {
+    fn from(e: io::Error) -> Self {
+        SaveError::Io(e)
+    }
+}
+
+impl From<ImageError> for SaveError {
+    fn from(e: ImageError) -> Self {
+        SaveError::Image(e)
+    }
+}
+
+impl From<exr::error::Error> for SaveError {
+    fn from(e: exr::error::Error) -> Self {
+        SaveError::Exr(e)
+    }
+}
+
+fn encode_linear(c: Vec3) -> [u8; 3] {
+    let c = c.map(|v| 255. * v.clamp(0., 1.) + 0.5);
+    [c[R] as u8, c[G] as u8, c[B] as u8]
+}
+
+/// Files are written here first and then renamed, so that an interrupted render never leaves
+/// a truncated image at `path`.
+fn partial_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".part");
+    path.with_file_name(name)
+}
+
+fn extension(path: &Path) -> String {
+    path.extension()
+        .map(|e| e.to_string_lossy().to_lowercase())
+        .unwrap_or_default()
+}
+
 impl Image {
+    #[allow(clippy::too_many_arguments)]
     pub fn cast(
         nx: usize,
         ny: usize,
@@ -16,19 +293,24 @@ impl Image {
         camera: Camera,
         background: Background,
         world: impl World,
+        config: &PathConfig,
         rng: &mut impl Rng,
     ) {
+        let pixel = (1. / (nx as f32 - 1.), 1. / (ny as f32 - 1.));
+
         Image::compute(nx, ny, |x, y| {
             (0..ns)
                 .map(|_| {
                     let u = (x as f32 + rng.gen::<f32>()) / (nx as f32 - 1.);
                     let v = (y as f32 + rng.gen::<f32>()) / (ny as f32 - 1.);
 
-                    let ray = camera.get_ray(u, v, rng);
-
-                    ray_color(ray, &world, &background, rng)
+                    match camera.get_ray_differential(u, v, pixel, rng) {
+                        Some(ray) => ray_color(ray, &world, &background, config, rng),
+                        None => Vec3::from(0.),
+                    }
                 })
                 .sum::<Vec3>()
+                * camera.exposure_scale()
                 / (ns as f32)
         });
     }
@@ -45,69 +327,299 @@ impl Image {
         )
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub fn par_cast(
         nx: usize,
         ny: usize,
         ns: usize,
+        seed: u64,
         camera: &Camera,
         background: Background,
         world: impl World,
+        config: &PathConfig,
     ) -> Image {
-        Image::par_compute(nx, ny, |x, y| {
-            let mut rng = thread_rng();
+        Image::par_cast_layers(nx, ny, ns, seed, camera, background, world, config, &[]).beauty
+    }
 
-            (0..ns)
-                .map(|_| {
-                    let u = (x as f32 + rng.gen::<f32>()) / (nx as f32 - 1.);
-                    let v = (y as f32 + rng.gen::<f32>()) / (ny as f32 - 1.);
+    /// Renders the beauty image and the given auxiliary passes.
+    ///
+    /// Passes are averaged over the pixel samples, except depth and ID which are taken
+    /// from the nearest sample.
+    #[allow(clippy::too_many_arguments)]
+    pub fn par_cast_layers(
+        nx: usize,
+        ny: usize,
+        ns: usize,
+        seed: u64,
+        camera: &Camera,
+        background: Background,
+        world: impl World,
+        config: &PathConfig,
+        passes: &[Pass],
+    ) -> Layers {
+        let pixel = (1. / (nx as f32 - 1.), 1. / (ny as f32 - 1.));
+
+        let pixels = Image::par_compute(nx, ny, |x, y| {
+            // Seeded per pixel so that the result doesn't depend on thread scheduling
+            let mut rng = StdRng::seed_from_u64(seed.wrapping_add((y * nx + x) as u64));
+
+            let mut color = Vec3::from(0.);
+            let mut aov: Option<FirstHit> = None;
+            // Vignetted samples are black in the beauty pass but leave the AOVs undefined
+            let mut taken = 0;
+
+            for _ in 0..ns {
+                let u = (x as f32 + rng.gen::<f32>()) / (nx as f32 - 1.);
+                let v = (y as f32 + rng.gen::<f32>()) / (ny as f32 - 1.);
+
+                let ray = match camera.get_ray_differential(u, v, pixel, &mut rng) {
+                    Some(ray) => ray,
+                    None => continue,
+                };
+
+                let (c, hit) = trace_path(ray, &world, &background, config, &mut rng);
+                color = color + c;
+                taken += 1;
+
+                aov = Some(match aov {
+                    None => hit,
+                    Some(acc) => {
+                        let nearest = if hit.depth < acc.depth { hit } else { acc };
+                        FirstHit {
+                            albedo: acc.albedo + hit.albedo,
+                            normal: acc.normal + hit.normal,
+                            position: acc.position + hit.position,
+                            uv: acc.uv + hit.uv,
+                            ..nearest
+                        }
+                    }
+                });
+            }
+
+            let n = taken as f32;
+            let aov = aov.map(|a| FirstHit {
+                albedo: a.albedo / n,
+                normal: if a.normal.near_zero() {
+                    a.normal
+                } else {
+                    a.normal.unit()
+                },
+                position: a.position / n,
+                uv: a.uv / n,
+                ..a
+            });
 
-                    let ray = camera.get_ray(u, v, &mut rng);
+            (color * camera.exposure_scale() / ns as f32, aov)
+        });
 
-                    ray_color(ray, &world, &background, &mut rng)
+        type Pixel = (Vec3, Option<FirstHit>);
+        let layer = |f: &dyn Fn(&Pixel) -> Vec3| {
+            Image(
+                pixels
+                    .iter()
+                    .map(|row| row.iter().map(f).collect())
+                    .collect(),
+            )
+        };
+
+        Layers {
+            beauty: layer(&|p| p.0),
+            passes: passes
+                .iter()
+                .map(|pass| {
+                    (
+                        *pass,
+                        layer(&|p| {
+                            p.1.map(|a| pass.value(&a))
+                                .unwrap_or_else(|| Vec3::from(0.))
+                        }),
+                    )
                 })
-                .sum::<Vec3>()
-                / (ns as f32)
-        })
+                .collect(),
+        }
     }
 
-    fn par_compute(nx: usize, ny: usize, f: impl Fn(usize, usize) -> Vec3 + Sync) -> Image {
+    fn par_compute<T: Send>(
+        nx: usize,
+        ny: usize,
+        f: impl Fn(usize, usize) -> T + Sync,
+    ) -> Vec<Vec<T>> {
         let progress_counter = Mutex::new(0_usize);
+        (0..ny)
+            .into_par_iter()
+            .rev()
+            .map(|y| {
+                {
+                    let mut progress = match progress_counter.lock() {
+                        Ok(data) => data,
+                        Err(e) => e.into_inner(),
+                    };
+                    *progress += 1;
+                    eprint!("\rscanlines: {} / {}", *progress, ny);
+                }
+                (0..nx).map(|x| f(x, y)).collect()
+            })
+            .collect()
+    }
+
+    fn map(&self, f: impl Fn(Vec3) -> Vec3) -> Image {
         Image(
-            (0..ny)
-                .into_par_iter()
-                .rev()
-                .map(|y| {
-                    {
-                        let mut progress = match progress_counter.lock() {
-                            Ok(data) => data,
-                            Err(e) => e.into_inner(),
-                        };
-                        *progress += 1;
-                        eprint!("\rscanlines: {} / {}", *progress, ny);
-                    }
-                    (0..nx).map(|x| f(x, y)).collect()
-                })
+            self.0
+                .iter()
+                .map(|row| row.iter().map(|c| f(*c)).collect())
                 .collect(),
         )
     }
 
-    pub fn print_ppm(self) {
-        println!("P3\n{}\t{}\n255", self.0[0].len(), self.0.len());
-        for row in self.0 {
-            for c in row {
-                let color = c.sqrt();
+    pub fn width(&self) -> usize {
+        self.0[0].len()
+    }
 
-                fn to_u8(v: f32) -> i32 {
-                    ((255.999 * v) as i32).max(0).min(255)
-                }
+    pub fn height(&self) -> usize {
+        self.0.len()
+    }
 
-                println!(
-                    "{}\t{}\t{}",
-                    to_u8(color[R]),
-                    to_u8(color[G]),
-                    to_u8(color[B])
-                );
+    /// Writes the image in a format chosen by the file extension.
+    ///
+    /// PNG, JPEG and PPM are tone mapped and sRGB encoded to 8 bits, Radiance HDR and
+    /// OpenEXR keep the linear radiance.
+    pub fn save(&self, path: impl AsRef<Path>, tone_map: &ToneMap) -> Result<(), SaveError> {
+        self.write(path.as_ref(), &|c| tone_map.encode(c), &|c| {
+            tone_map.working_space.to_rec709(c)
+        })
+    }
+
+    /// Like `save`, but stores values linearly in 8-bit formats. Meant for data such as
+    /// normals that shouldn't be gamma encoded.
+    pub fn save_linear(&self, path: impl AsRef<Path>) -> Result<(), SaveError> {
+        self.write(path.as_ref(), &encode_linear, &|c| c)
+    }
+
+    /// `encode` converts to 8-bit formats, `float` to float formats.
+    fn write(
+        &self,
+        path: &Path,
+        encode: &dyn Fn(Vec3) -> [u8; 3],
+        float: &(dyn Fn(Vec3) -> Vec3 + Sync),
+    ) -> Result<(), SaveError> {
+        let ext = extension(path);
+        let partial = partial_path(path);
+
+        match ext.as_str() {
+            "png" => self
+                .to_rgb8(encode)
+                .save_with_format(&partial, ImageFormat::Png)?,
+            "jpg" | "jpeg" => self
+                .to_rgb8(encode)
+                .save_with_format(&partial, ImageFormat::Jpeg)?,
+            "ppm" => self.write_ppm_with(&mut BufWriter::new(File::create(&partial)?), encode)?,
+            "hdr" => {
+                let data: Vec<Rgb<f32>> = self
+                    .0
+                    .iter()
+                    .flatten()
+                    .map(|c| {
+                        let c = float(*c);
+                        Rgb([c[R], c[G], c[B]])
+                    })
+                    .collect();
+
+                HdrEncoder::new(BufWriter::new(File::create(&partial)?)).encode(
+                    &data,
+                    self.width(),
+                    self.height(),
+                )?
             }
+            "exr" => {
+                exr::prelude::write_rgb_file(&partial, self.width(), self.height(), |x, y| {
+                    let c = float(self.0[y][x]);
+                    (c[R], c[G], c[B])
+                })?
+            }
+            _ => return Err(SaveError::UnsupportedFormat(ext)),
+        }
+
+        fs::rename(&partial, path)?;
+
+        Ok(())
+    }
+
+    fn to_rgb8(&self, encode: &dyn Fn(Vec3) -> [u8; 3]) -> RgbImage {
+        RgbImage::from_fn(self.width() as u32, self.height() as u32, |x, y| {
+            Rgb(encode(self.0[y as usize][x as usize]))
+        })
+    }
+
+    pub fn print_ppm(self, tone_map: &ToneMap) {
+        self.write_ppm(&mut io::stdout().lock(), tone_map)
+            .expect("Failed to write to stdout.");
+    }
+
+    pub fn write_ppm(&self, out: &mut impl Write, tone_map: &ToneMap) -> io::Result<()> {
+        self.write_ppm_with(out, &|c| tone_map.encode(c))
+    }
+
+    fn write_ppm_with(
+        &self,
+        out: &mut impl Write,
+        encode: &dyn Fn(Vec3) -> [u8; 3],
+    ) -> io::Result<()> {
+        writeln!(out, "P3\n{}\t{}\n255", self.width(), self.height())?;
+        for row in &self.0 {
+            for c in row {
+                let [r, g, b] = encode(*c);
+                writeln!(out, "{}\t{}\t{}", r, g, b)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::material::Material;
+    use crate::primitive::{Hittable, Sphere};
+    use std::sync::Arc;
+
+    #[test]
+    fn aovs_average_the_samples_taken() {
+        let albedo = Vec3::new(0.2, 0.4, 0.6);
+        let objs: Vec<Arc<dyn Hittable>> = vec![Arc::new(Sphere {
+            center: Vec3::from(0.),
+            radius: 10.,
+            material: Material::Lambertian {
+                albedo: albedo.into(),
+            },
+        })];
+
+        // Inside the sphere, with the lens half blocked towards the corners
+        let camera = Camera::new(
+            Vec3::from(0.),
+            Vec3::new(0., 0., -1.),
+            Vec3::new(0., 1., 0.),
+            90.,
+            1.,
+            5.,
+            1.,
+            0.0..0.0,
+        )
+        .with_cat_eye(1.);
+
+        let layers = Image::par_cast_layers(
+            4,
+            4,
+            16,
+            0,
+            &camera,
+            Box::new(|_| Vec3::from(0.)),
+            &objs[..],
+            &PathConfig::default(),
+            &[Pass::Albedo],
+        );
+
+        for c in layers.passes[0].1 .0.iter().flatten() {
+            assert!((*c - albedo).len() < 1e-5, "{:?}", c);
         }
     }
 }
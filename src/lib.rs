@@ -1,12 +1,14 @@
 #![feature(min_const_generics)]
 
 pub const EPSILON: f32 = 0.001;
-pub const MAX_BOUNCES: usize = 5;
 
 mod aabb;
+pub mod assets;
 pub mod bvh;
 pub mod camera;
+pub mod color;
 pub mod image;
+pub mod light;
 pub mod material;
 pub mod math;
 pub mod mesh;
@@ -14,5 +16,8 @@ mod perlin;
 pub mod preview;
 pub mod primitive;
 pub mod ray;
+pub mod scene;
 pub mod texture;
+pub mod timeline;
+pub mod tonemap;
 pub mod world;
@@ -0,0 +1,389 @@
+use crate::material::Material;
+use crate::math::{Onb, Vec3};
+use crate::primitive::{sphere_uv, HitRecord, Transform};
+use crate::ray::Ray;
+use crate::EPSILON;
+
+use rand::Rng;
+use std::f32::consts::PI;
+use std::sync::Arc;
+
+/// Emissive geometry that can be sampled directly.
+#[derive(Clone)]
+pub struct Light {
+    shape: Shape,
+    material: Material,
+    /// Moves `shape` with the ray time, for lights inside moving objects.
+    motion: Option<Motion>,
+}
+
+type Motion = Arc<dyn Fn(f32) -> Transform + Send + Sync>;
+
+#[derive(Copy, Clone)]
+enum Shape {
+    Sphere { center: Vec3, radius: f32 },
+    Triangle { vertices: (Vec3, Vec3, Vec3) },
+}
+
+pub struct LightSample {
+    /// Unit direction from the shading point towards the light.
+    pub dir: Vec3,
+    pub distance: f32,
+    /// Solid angle density of `dir`.
+    pub pdf: f32,
+    pub emitted: Vec3,
+}
+
+impl Light {
+    pub fn sphere(center: Vec3, radius: f32, material: Material) -> Self {
+        Light {
+            shape: Shape::Sphere { center, radius },
+            material,
+            motion: None,
+        }
+    }
+
+    pub fn triangle(vertices: (Vec3, Vec3, Vec3), material: Material) -> Self {
+        Light {
+            shape: Shape::Triangle { vertices },
+            material,
+            motion: None,
+        }
+    }
+
+    /// The light moved by the affine transform `t`. Spheres only stay spheres under uniform
+    /// scale, so `None` is returned otherwise and such lights are only found by BSDF sampling.
+    pub fn transform(&self, t: &Transform) -> Option<Light> {
+        match &self.motion {
+            None => Some(Light {
+                shape: self.shape.transform(t)?,
+                ..self.clone()
+            }),
+            Some(motion) => {
+                let (t, motion) = (*t, motion.clone());
+                Some(self.moving(move |time| t * motion(time)))
+            }
+        }
+    }
+
+    /// The light moved by `motion`, the transform at each ray time. A sphere that isn't
+    /// scaled uniformly at some time can't be sampled then.
+    pub fn moving(&self, motion: impl Fn(f32) -> Transform + Send + Sync + 'static) -> Light {
+        let motion: Motion = match &self.motion {
+            None => Arc::new(motion),
+            Some(inner) => {
+                let inner = inner.clone();
+                Arc::new(move |time| motion(time) * inner(time))
+            }
+        };
+
+        Light {
+            motion: Some(motion),
+            ..self.clone()
+        }
+    }
+
+    /// Shape at `time`, if it can be sampled then.
+    fn shape_at(&self, time: f32) -> Option<Shape> {
+        match &self.motion {
+            None => Some(self.shape),
+            Some(motion) => self.shape.transform(&motion(time)),
+        }
+    }
+
+    /// Samples a direction from `origin` towards the light.
+    pub fn sample(&self, origin: Vec3, time: f32, rng: &mut impl Rng) -> Option<LightSample> {
+        let (point, normal, uv, pdf) = match self.shape_at(time)? {
+            Shape::Sphere { center, radius } => {
+                // Sample the cone of directions subtended by the sphere
+                let d = center - origin;
+                let dist_sqr = d.len_sqr();
+
+                if dist_sqr <= radius * radius {
+                    return None;
+                }
+
+                let cos_max = (1. - radius * radius / dist_sqr).sqrt();
+                let cos_theta = 1. - rng.gen::<f32>() * (1. - cos_max);
+                let sin_theta = (1. - cos_theta * cos_theta).max(0.).sqrt();
+                let phi = 2. * PI * rng.gen::<f32>();
+
+                let dir = Onb::from_w(d).local(Vec3::new(
+                    phi.cos() * sin_theta,
+                    phi.sin() * sin_theta,
+                    cos_theta,
+                ));
+
+                let t = sphere_intersect(center, radius, origin, dir)?;
+                let point = origin + t * dir;
+                let n = (point - center) / radius;
+
+                (point, n, sphere_uv(n), 1. / (2. * PI * (1. - cos_max)))
+            }
+            Shape::Triangle {
+                vertices: (v1, v2, v3),
+            } => {
+                let su = rng.gen::<f32>().sqrt();
+                let u = rng.gen::<f32>() * su;
+                let v = 1. - su;
+                let point = (1. - u - v) * v1 + u * v2 + v * v3;
+
+                let n = Vec3::cross(v2 - v1, v3 - v1);
+                let area = 0.5 * n.len();
+                let n = n.unit();
+
+                let to_light = point - origin;
+                let dist_sqr = to_light.len_sqr();
+                let cos_light = Vec3::dot(n, to_light.unit()).abs();
+
+                if cos_light < 1e-6 {
+                    return None;
+                }
+
+                (point, n, Vec3::new(u, v, 0.), dist_sqr / (cos_light * area))
+            }
+        };
+
+        let ray = Ray {
+            origin,
+            dir: point - origin,
+            t: time,
+            differential: None,
+        };
+
+        Some(LightSample {
+            dir: ray.dir.unit(),
+            distance: ray.dir.len(),
+            pdf,
+            emitted: self
+                .material
+                .emitted(&HitRecord::new(&ray, 1., normal, uv, &self.material)),
+        })
+    }
+
+    /// Solid angle density with which `sample` would produce the direction of `ray`.
+    pub fn pdf(&self, ray: &Ray) -> f32 {
+        let dir = ray.dir.unit();
+        let shape = match self.shape_at(ray.t) {
+            Some(shape) => shape,
+            None => return 0.,
+        };
+
+        match shape {
+            Shape::Sphere { center, radius } => {
+                let dist_sqr = (center - ray.origin).len_sqr();
+
+                if dist_sqr <= radius * radius
+                    || sphere_intersect(center, radius, ray.origin, dir).is_none()
+                {
+                    return 0.;
+                }
+
+                let cos_max = (1. - radius * radius / dist_sqr).sqrt();
+                1. / (2. * PI * (1. - cos_max))
+            }
+            Shape::Triangle { vertices } => match triangle_intersect(vertices, ray.origin, dir) {
+                None => 0.,
+                Some(t) => {
+                    let (v1, v2, v3) = vertices;
+                    let n = Vec3::cross(v2 - v1, v3 - v1);
+                    let area = 0.5 * n.len();
+                    let cos_light = Vec3::dot(n.unit(), dir).abs();
+
+                    if cos_light < 1e-6 {
+                        0.
+                    } else {
+                        t * t / (cos_light * area)
+                    }
+                }
+            },
+        }
+    }
+}
+
+impl Shape {
+    fn transform(&self, t: &Transform) -> Option<Shape> {
+        Some(match *self {
+            Shape::Sphere { center, radius } => {
+                let scale = [
+                    t.vector(Vec3::new(1., 0., 0.)).len(),
+                    t.vector(Vec3::new(0., 1., 0.)).len(),
+                    t.vector(Vec3::new(0., 0., 1.)).len(),
+                ];
+
+                if (scale[0] - scale[1]).abs() > 1e-4 * scale[0]
+                    || (scale[0] - scale[2]).abs() > 1e-4 * scale[0]
+                {
+                    return None;
+                }
+
+                Shape::Sphere {
+                    center: t.point(center),
+                    radius: radius * scale[0],
+                }
+            }
+            Shape::Triangle {
+                vertices: (v1, v2, v3),
+            } => Shape::Triangle {
+                vertices: (t.point(v1), t.point(v2), t.point(v3)),
+            },
+        })
+    }
+}
+
+/// Nearest positive intersection distance along the unit direction `dir`.
+fn sphere_intersect(center: Vec3, radius: f32, origin: Vec3, dir: Vec3) -> Option<f32> {
+    let oc = origin - center;
+    let hb = Vec3::dot(oc, dir);
+    let c = oc.len_sqr() - radius * radius;
+    let discriminant = hb * hb - c;
+
+    if discriminant < 0. {
+        return None;
+    }
+
+    let sq = discriminant.sqrt();
+    [-hb - sq, -hb + sq].iter().copied().find(|t| *t > EPSILON)
+}
+
+fn triangle_intersect((v1, v2, v3): (Vec3, Vec3, Vec3), origin: Vec3, dir: Vec3) -> Option<f32> {
+    let edge1 = v2 - v1;
+    let edge2 = v3 - v1;
+
+    let h = Vec3::cross(dir, edge2);
+    let a = Vec3::dot(edge1, h);
+
+    if a.abs() < 1e-8 {
+        return None;
+    }
+
+    let f = 1. / a;
+    let s = origin - v1;
+    let u = f * Vec3::dot(s, h);
+    let q = Vec3::cross(s, edge1);
+    let v = f * Vec3::dot(dir, q);
+
+    if u < 0. || v < 0. || u + v > 1. {
+        return None;
+    }
+
+    Some(f * Vec3::dot(edge2, q)).filter(|t| *t > EPSILON)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::primitive::{Hittable, Keyframe, LinearMove, MotionTransform, Sphere};
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    fn emitter(center: Vec3) -> Sphere {
+        Sphere {
+            center,
+            radius: 0.5,
+            material: Material::DiffuseLight {
+                emit: Vec3::from(1.).into(),
+                intensity: 1.,
+            },
+        }
+    }
+
+    fn ray(dir: Vec3, t: f32) -> Ray {
+        Ray {
+            origin: Vec3::from(0.),
+            dir,
+            t,
+            differential: None,
+        }
+    }
+
+    /// Checks that the only light of `object` is at `+x * 5` at time 0 and `+y * 5` at time 1.
+    fn check_moving(object: &dyn Hittable) {
+        let lights = object.lights();
+        assert_eq!(lights.len(), 1);
+
+        let light = &lights[0];
+        let mut rng = StdRng::seed_from_u64(0);
+        let sample = light.sample(Vec3::from(0.), 1., &mut rng).unwrap();
+
+        assert!(sample.dir.y() > 0.9, "{:?}", sample.dir);
+        assert!(light.pdf(&ray(Vec3::new(1., 0., 0.), 0.)) > 0.);
+        assert_eq!(light.pdf(&ray(Vec3::new(1., 0., 0.), 1.)), 0.);
+        assert!(light.pdf(&ray(Vec3::new(0., 1., 0.), 1.)) > 0.);
+    }
+
+    #[test]
+    fn linear_move_lights_follow_the_object() {
+        check_moving(&LinearMove {
+            object: emitter(Vec3::new(5., 0., 0.)),
+            velocity: Vec3::new(-5., 5., 0.),
+        });
+    }
+
+    #[test]
+    fn motion_transform_lights_follow_the_object() {
+        let keyframes = vec![
+            Keyframe::new(0., &Transform::translate(Vec3::new(5., 0., 0.))),
+            Keyframe::new(1., &Transform::translate(Vec3::new(0., 5., 0.))),
+        ];
+
+        check_moving(&MotionTransform::new(emitter(Vec3::from(0.)), keyframes));
+    }
+
+    fn lights() -> [Light; 2] {
+        let material = || Material::DiffuseLight {
+            emit: Vec3::from(1.).into(),
+            intensity: 1.,
+        };
+
+        [
+            Light::sphere(Vec3::new(0., 0., 2.), 1., material()),
+            Light::triangle(
+                (
+                    Vec3::new(-1., -1., 1.),
+                    Vec3::new(2., -1., 1.5),
+                    Vec3::new(-1., 2., 0.5),
+                ),
+                material(),
+            ),
+        ]
+    }
+
+    #[test]
+    fn pdf_matches_sample_pdf() {
+        let mut rng = StdRng::seed_from_u64(1);
+
+        for light in lights() {
+            for _ in 0..1000 {
+                let sample = match light.sample(Vec3::from(0.), 0., &mut rng) {
+                    Some(sample) => sample,
+                    None => continue,
+                };
+                let pdf = light.pdf(&ray(sample.dir, 0.));
+
+                assert!(
+                    (pdf - sample.pdf).abs() < 1e-3 * sample.pdf,
+                    "{} != {}",
+                    pdf,
+                    sample.pdf
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn pdf_integrates_to_one() {
+        let mut rng = StdRng::seed_from_u64(2);
+        let n = 400_000;
+
+        for light in lights() {
+            // Uniform directions over the sphere have density 1 / 4π
+            let mean = (0..n)
+                .map(|_| light.pdf(&ray(Vec3::rand_in_unit_sphere(&mut rng), 0.)))
+                .sum::<f32>()
+                / n as f32;
+
+            assert!((4. * PI * mean - 1.).abs() < 0.02, "{}", 4. * PI * mean);
+        }
+    }
+}
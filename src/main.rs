@@ -1,27 +1,169 @@
+use sade_h::assets::Assets;
 use sade_h::bvh::Bvh;
 use sade_h::camera::Camera;
-use sade_h::image::Image;
+use sade_h::color::{ColorSpace, WorkingSpace};
+use sade_h::image::{Image, Layers, Pass};
 use sade_h::material::Material;
 use sade_h::math::{Axis3::*, Vec3};
-use sade_h::primitive::{ConstantMedium, Hittable, LinearMove, Sphere, Transform, Triangle};
-use sade_h::texture::{checker, image, marbled, perlin_turb, solid, Texture};
+use sade_h::primitive::{
+    ConstantMedium, Hittable, LinearMove, Sphere, Tagged, Transform, Triangle,
+};
+use sade_h::texture::{checker, image, marbled, perlin_turb, solid, Sampler, Texture};
 
 use rand::{thread_rng, Rng, SeedableRng};
 use sade_h::mesh::Mesh;
 use sade_h::preview::Preview;
-use sade_h::world::Background;
+use sade_h::scene::{self, Scene};
+use sade_h::timeline::Timeline;
+use sade_h::tonemap::{Operator, ToneMap};
+use sade_h::world::{Background, PathConfig};
 use std::ops::Range;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use structopt::StructOpt;
+
+#[derive(StructOpt)]
+#[structopt(name = "sade", about = "A path tracer.")]
+struct Opts {
+    /// Built-in scene name or path to a .ron scene file
+    #[structopt(short, long, default_value = "bunny")]
+    scene: String,
+
+    /// Image width in pixels
+    #[structopt(short, long, default_value = "1200")]
+    width: usize,
+
+    /// Image height in pixels [default: width / 1.5]
+    #[structopt(long)]
+    height: Option<usize>,
+
+    /// Samples per pixel
+    #[structopt(short = "n", long, default_value = "100")]
+    samples: usize,
+
+    /// Start of the exposure interval
+    #[structopt(long, default_value = "0")]
+    shutter_open: f32,
+
+    /// End of the exposure interval
+    #[structopt(long, default_value = "1")]
+    shutter_close: f32,
+
+    /// Bounces before Russian roulette may terminate a path
+    #[structopt(long, default_value = "3")]
+    min_depth: usize,
+
+    /// Maximum number of bounces
+    #[structopt(long, default_value = "64")]
+    max_depth: usize,
+
+    /// Maximum number of diffuse bounces
+    #[structopt(long, default_value = "16")]
+    max_diffuse: usize,
+
+    /// Maximum number of specular bounces off metals and dielectrics
+    #[structopt(long, default_value = "64")]
+    max_specular: usize,
+
+    /// Maximum number of scattering events in participating media
+    #[structopt(long, default_value = "64")]
+    max_volume: usize,
+
+    /// Rolling shutter readout time, overriding the scene's. Each row is exposed for the
+    /// exposure interval minus the readout, the top row first
+    #[structopt(long)]
+    readout: Option<f32>,
+
+    /// Render an animation of this many frames, numbered from 1. Each frame is written to the
+    /// output path with its number appended, e.g. frame_0001.png
+    #[structopt(long)]
+    frames: Option<usize>,
+
+    /// Frames per second of the animation
+    #[structopt(long, default_value = "24")]
+    fps: f32,
+
+    /// Portion of each frame the shutter is open in degrees, replacing the exposure interval
+    /// in animations
+    #[structopt(long, default_value = "180")]
+    shutter_angle: f32,
+
+    /// First frame to render
+    #[structopt(long, default_value = "1")]
+    first_frame: usize,
+
+    /// Skip frames whose output already exists
+    #[structopt(long)]
+    resume: bool,
+
+    /// Seed for the batch renderer [default: random]
+    #[structopt(long)]
+    seed: Option<u64>,
+
+    /// Output file, format chosen by extension: png, jpg, ppm, hdr or exr [default: PPM to stdout]
+    #[structopt(short, long, parse(from_os_str))]
+    output: Option<PathBuf>,
+
+    /// Auxiliary passes to write alongside the image: albedo, normal, depth, position, uv,
+    /// id (per object) or material-id (per scene file material). Layers of the same file for
+    /// EXR, separate files otherwise
+    #[structopt(long = "pass", parse(try_from_str = parse_pass), use_delimiter = true)]
+    passes: Vec<Pass>,
+
+    /// Tone mapping operator for 8-bit output and the preview: clamp, reinhard,
+    /// extended-reinhard, aces or agx
+    #[structopt(long, default_value = "clamp", parse(try_from_str = parse_operator))]
+    tonemap: Operator,
+
+    /// Exposure compensation in stops, applied before tone mapping
+    #[structopt(long, default_value = "0", allow_hyphen_values = true)]
+    ev: f32,
+
+    /// Luminance that extended-reinhard maps to white
+    #[structopt(long, default_value = "4")]
+    white: f32,
+
+    /// Linear color space to render in: rec709 or acescg. Colors in scene files are taken to be
+    /// in it. Built-in scenes always render in rec709
+    #[structopt(long, default_value = "rec709", parse(try_from_str = parse_working_space))]
+    working_space: WorkingSpace,
+
+    /// Open an interactive, progressively refined preview instead of rendering to a file
+    #[structopt(short, long)]
+    preview: bool,
+
+    /// List built-in scenes and exit
+    #[structopt(long)]
+    list_scenes: bool,
+}
 
-const SAMPLES: usize = 100;
-const ASPECT_RATIO: f32 = 3. / 2.;
-const WIDTH: usize = 1200;
-const HEIGHT: usize = (WIDTH as f32 / ASPECT_RATIO) as usize;
+fn parse_pass(name: &str) -> Result<Pass, String> {
+    Pass::from_name(name).ok_or_else(|| format!("unknown pass \"{}\"", name))
+}
 
-type Scene = (Camera, Vec<Box<dyn Hittable>>, Background);
+fn parse_working_space(name: &str) -> Result<WorkingSpace, String> {
+    WorkingSpace::from_name(name).ok_or_else(|| format!("unknown working space \"{}\"", name))
+}
 
-#[allow(dead_code)]
-fn sphere_scene(exposure: Range<f32>) -> Scene {
+fn parse_operator(name: &str) -> Result<Operator, String> {
+    Operator::from_name(name).ok_or_else(|| format!("unknown tone mapping operator \"{}\"", name))
+}
+
+type Preset = fn(Range<f32>, f32, &mut Assets) -> Scene;
+
+const PRESETS: &[(&str, Preset)] = &[
+    ("sphere", sphere_scene),
+    ("perlin", perlin_scene),
+    ("earth", earth_scene),
+    ("earth-lights", earth_lights_scene),
+    ("checker", checker_scene),
+    ("triangle", triangle_scene),
+    ("bunny", bunny_scene),
+    ("constant-medium", constant_medium_scene),
+    ("cornell-box", cornell_box_scene),
+];
+
+fn sphere_scene(exposure: Range<f32>, aspect_ratio: f32, _: &mut Assets) -> Scene {
     let camera = {
         let lookfrom = Vec3::new(13., 2., 3.);
         let lookat = Vec3::new(0., 0., 0.);
@@ -31,7 +173,7 @@ fn sphere_scene(exposure: Range<f32>) -> Scene {
             lookat,
             Vec3::new(0., 1., 0.),
             20.,
-            ASPECT_RATIO,
+            aspect_ratio,
             10.,
             0.1,
             exposure,
@@ -149,8 +291,7 @@ fn sphere_scene(exposure: Range<f32>) -> Scene {
     )
 }
 
-#[allow(dead_code)]
-fn perlin_scene(exposure: Range<f32>) -> Scene {
+fn perlin_scene(exposure: Range<f32>, aspect_ratio: f32, _: &mut Assets) -> Scene {
     let camera = {
         let lookfrom = Vec3::new(13., 2., 3.);
         let lookat = Vec3::new(0., 0., 0.);
@@ -160,7 +301,7 @@ fn perlin_scene(exposure: Range<f32>) -> Scene {
             lookat,
             Vec3::new(0., 1., 0.),
             20.,
-            ASPECT_RATIO,
+            aspect_ratio,
             10.,
             0.1,
             exposure,
@@ -200,8 +341,7 @@ fn perlin_scene(exposure: Range<f32>) -> Scene {
     )
 }
 
-#[allow(dead_code)]
-fn earth_scene(exposure: Range<f32>) -> Scene {
+fn earth_scene(exposure: Range<f32>, aspect_ratio: f32, assets: &mut Assets) -> Scene {
     let camera = {
         let lookfrom = Vec3::new(13., 2., 3.);
         let lookat = Vec3::new(0., 2., 0.);
@@ -211,7 +351,7 @@ fn earth_scene(exposure: Range<f32>) -> Scene {
             lookat,
             Vec3::new(0., 1., 0.),
             30.,
-            ASPECT_RATIO,
+            aspect_ratio,
             10.,
             0.1,
             exposure,
@@ -224,7 +364,14 @@ fn earth_scene(exposure: Range<f32>) -> Scene {
             albedo: marbled(4., &mut rng),
         };
         let mat_earth = Material::Lambertian {
-            albedo: image("./earthmap.jpg".to_string()),
+            albedo: image(
+                "./assets/earthmap.jpg".to_string(),
+                ColorSpace::Srgb,
+                WorkingSpace::Rec709,
+                Sampler::default(),
+                assets,
+            )
+            .expect("Failed to load ./assets/earthmap.jpg."),
         };
 
         let mut world: Vec<Box<dyn Hittable>> = vec![];
@@ -254,8 +401,7 @@ fn earth_scene(exposure: Range<f32>) -> Scene {
     )
 }
 
-#[allow(dead_code)]
-fn earth_lights_scene(exposure: Range<f32>) -> Scene {
+fn earth_lights_scene(exposure: Range<f32>, aspect_ratio: f32, assets: &mut Assets) -> Scene {
     let camera = {
         let lookfrom = Vec3::new(13., 2., 3.);
         let lookat = Vec3::new(0., 2., 0.);
@@ -265,7 +411,7 @@ fn earth_lights_scene(exposure: Range<f32>) -> Scene {
             lookat,
             Vec3::new(0., 1., 0.),
             30.,
-            ASPECT_RATIO,
+            aspect_ratio,
             10.,
             0.1,
             exposure,
@@ -278,7 +424,14 @@ fn earth_lights_scene(exposure: Range<f32>) -> Scene {
             albedo: marbled(4., &mut rng),
         };
         let mat_earth = Material::Lambertian {
-            albedo: image("./earthmap.jpg".to_string()),
+            albedo: image(
+                "./assets/earthmap.jpg".to_string(),
+                ColorSpace::Srgb,
+                WorkingSpace::Rec709,
+                Sampler::default(),
+                assets,
+            )
+            .expect("Failed to load ./assets/earthmap.jpg."),
         };
         let mat_light = Material::DiffuseLight {
             emit: solid(Vec3::new(1., 1., 1.)),
@@ -311,8 +464,7 @@ fn earth_lights_scene(exposure: Range<f32>) -> Scene {
     (camera, world, Box::new(|_| Vec3::new(0., 0., 0.)))
 }
 
-#[allow(dead_code)]
-fn checker_scene(exposure: Range<f32>) -> Scene {
+fn checker_scene(exposure: Range<f32>, aspect_ratio: f32, _: &mut Assets) -> Scene {
     let camera = {
         let lookfrom = Vec3::new(13., 2., 3.);
         let lookat = Vec3::new(0., 0., 0.);
@@ -322,7 +474,7 @@ fn checker_scene(exposure: Range<f32>) -> Scene {
             lookat,
             Vec3::new(0., 1., 0.),
             20.,
-            ASPECT_RATIO,
+            aspect_ratio,
             10.,
             0.1,
             exposure,
@@ -364,8 +516,7 @@ fn checker_scene(exposure: Range<f32>) -> Scene {
     )
 }
 
-#[allow(dead_code)]
-fn triangle_scene(exposure: Range<f32>) -> Scene {
+fn triangle_scene(exposure: Range<f32>, aspect_ratio: f32, _: &mut Assets) -> Scene {
     let camera = {
         let lookfrom = Vec3::new(0., 0., -3.);
         let lookat = Vec3::new(0., 0., 0.);
@@ -375,7 +526,7 @@ fn triangle_scene(exposure: Range<f32>) -> Scene {
             lookat,
             Vec3::new(0., 1., 0.),
             45.,
-            ASPECT_RATIO,
+            aspect_ratio,
             10.,
             0.1,
             exposure,
@@ -418,8 +569,7 @@ fn triangle_scene(exposure: Range<f32>) -> Scene {
     )
 }
 
-#[allow(dead_code)]
-fn bunny_scene(exposure: Range<f32>) -> Scene {
+fn bunny_scene(exposure: Range<f32>, aspect_ratio: f32, assets: &mut Assets) -> Scene {
     let camera = {
         let lookfrom = Vec3::new(0., -3., -20.);
         let lookat = Vec3::new(0., 0., 0.);
@@ -429,7 +579,7 @@ fn bunny_scene(exposure: Range<f32>) -> Scene {
             lookat,
             Vec3::new(0., 1., 0.),
             30.,
-            ASPECT_RATIO,
+            aspect_ratio,
             (lookat - lookfrom).len(),
             0.01,
             exposure.clone(),
@@ -439,7 +589,7 @@ fn bunny_scene(exposure: Range<f32>) -> Scene {
     let world = {
         let mut world: Vec<Box<dyn Hittable>> = vec![];
 
-        let mut bunny = Mesh::load(
+        let bunny = Mesh::load(
             "./assets/bunny-with-normals.obj".to_string(),
             &Transform::stack(
                 [
@@ -454,9 +604,11 @@ fn bunny_scene(exposure: Range<f32>) -> Scene {
                 fuzz: 0.0,
                 ior: 1.5,
             },
-        );
+            assets,
+        )
+        .expect("Failed to load ./assets/bunny-with-normals.obj.");
 
-        world.append(&mut bunny);
+        world.push(Box::new(bvh(bunny, exposure.clone())));
 
         world.push(Box::new(Sphere {
             center: Vec3::new(0., -1005., 0.),
@@ -468,7 +620,14 @@ fn bunny_scene(exposure: Range<f32>) -> Scene {
 
         world.push(Box::new(Sphere {
             material: Material::Metal {
-                albedo: image("./assets/earthmap.jpg".to_string()),
+                albedo: image(
+                    "./assets/earthmap.jpg".to_string(),
+                    ColorSpace::Srgb,
+                    WorkingSpace::Rec709,
+                    Sampler::default(),
+                    assets,
+                )
+                .expect("Failed to load ./assets/earthmap.jpg."),
                 fuzz: 0.7,
             },
             center: Vec3::new(6., 0., 0.),
@@ -506,8 +665,7 @@ fn bunny_scene(exposure: Range<f32>) -> Scene {
     )
 }
 
-#[allow(dead_code)]
-fn constant_medium_scene(exposure: Range<f32>) -> Scene {
+fn constant_medium_scene(exposure: Range<f32>, aspect_ratio: f32, assets: &mut Assets) -> Scene {
     let camera = {
         let lookfrom = Vec3::new(0., 3., -20.);
         let lookat = Vec3::new(0., 0., 0.);
@@ -517,7 +675,7 @@ fn constant_medium_scene(exposure: Range<f32>) -> Scene {
             lookat,
             Vec3::new(0., 1., 0.),
             30.,
-            ASPECT_RATIO,
+            aspect_ratio,
             (lookat - lookfrom).len(),
             0.01,
             exposure.clone(),
@@ -551,7 +709,7 @@ fn constant_medium_scene(exposure: Range<f32>) -> Scene {
         )));
 
         let bunny = Mesh::load(
-            "./bunny-with-normals.obj".to_string(),
+            "./assets/bunny-with-normals.obj".to_string(),
             &Transform::stack(
                 [
                     Transform::rotate(0., -std::f32::consts::PI, 0.),
@@ -561,10 +719,12 @@ fn constant_medium_scene(exposure: Range<f32>) -> Scene {
                 .iter(),
             ),
             Material::Empty,
-        );
+            assets,
+        )
+        .expect("Failed to load ./assets/bunny-with-normals.obj.");
 
         world.push(Box::new(ConstantMedium::new(
-            Box::new(Bvh::new(bunny, exposure.clone())),
+            Box::new(bvh(bunny, exposure.clone())),
             Material::Isotropic {
                 albedo: Vec3::new(0.2, 0.4, 0.6),
             },
@@ -584,8 +744,7 @@ fn constant_medium_scene(exposure: Range<f32>) -> Scene {
     )
 }
 
-#[allow(dead_code)]
-fn cornell_box_scene(exposure: Range<f32>) -> Scene {
+fn cornell_box_scene(exposure: Range<f32>, aspect_ratio: f32, assets: &mut Assets) -> Scene {
     let camera = {
         let x = 2.;
         let y = 21.;
@@ -597,7 +756,7 @@ fn cornell_box_scene(exposure: Range<f32>) -> Scene {
             lookat,
             Vec3::new(0., 1., 0.),
             90.,
-            ASPECT_RATIO,
+            aspect_ratio,
             (lookat - lookfrom).len(),
             0.01,
             exposure.clone(),
@@ -610,7 +769,7 @@ fn cornell_box_scene(exposure: Range<f32>) -> Scene {
             albedo: marbled(2., &mut rng),
         };
 
-        let world: Vec<Box<dyn Hittable>> = Mesh::load(
+        let cornell_box = Mesh::load(
             "./assets/cornell-box.obj".to_string(),
             &Transform::stack(
                 [
@@ -620,7 +779,11 @@ fn cornell_box_scene(exposure: Range<f32>) -> Scene {
                 .iter(),
             ),
             mat,
-        );
+            assets,
+        )
+        .expect("Failed to load ./assets/cornell-box.obj.");
+
+        let world: Vec<Box<dyn Hittable>> = vec![Box::new(bvh(cornell_box, exposure.clone()))];
 
         world
     };
@@ -635,39 +798,206 @@ fn cornell_box_scene(exposure: Range<f32>) -> Scene {
     )
 }
 
-/* fn load_scene(path: String) -> Scene {
-    ron::from_str(&*std::fs::read_to_string(path.as_str()).expect("Couldn't load scene."))
-        .expect("Failed to deserialize scene.")
-} */
+/// Builds a BVH over `objs` and reports how long it took.
+fn bvh(objs: Vec<Box<dyn Hittable>>, exposure: Range<f32>) -> Bvh {
+    let bvh = Bvh::new(objs, exposure);
+    eprintln!("bvh: {}", bvh.stats());
+    bvh
+}
+
+/// Built-in scenes are always in Rec. 709, scene files in the chosen working space.
+fn working_space(opts: &Opts) -> WorkingSpace {
+    if PRESETS.iter().any(|(name, _)| *name == opts.scene) {
+        WorkingSpace::Rec709
+    } else {
+        opts.working_space
+    }
+}
+
+/// Loads the selected scene for the given exposure and puts it in a BVH, tagging each
+/// top-level object with an ID. Files are read through `assets`.
+fn load(
+    opts: &Opts,
+    aspect_ratio: f32,
+    exposure: Range<f32>,
+    assets: &mut Assets,
+) -> (Camera, Bvh, Background) {
+    let (camera, world, background) = match PRESETS.iter().find(|(name, _)| *name == opts.scene) {
+        Some((_, preset)) => preset(exposure.clone(), aspect_ratio, assets),
+        None => scene::load(
+            &opts.scene,
+            aspect_ratio,
+            exposure.clone(),
+            working_space(opts),
+            assets,
+        )
+        .unwrap_or_else(|e| {
+            eprintln!("{}: {}", opts.scene, e);
+            std::process::exit(1);
+        }),
+    };
+
+    let camera = match opts.readout {
+        Some(readout) => camera.with_rolling_shutter(readout),
+        None => camera,
+    };
+
+    let world = world
+        .into_iter()
+        .enumerate()
+        .map(|(i, object)| -> Box<dyn Hittable> {
+            Box::new(Tagged {
+                object,
+                id: i as u32 + 1,
+            })
+        })
+        .collect();
+
+    // The camera may have its own shutter speed
+    let exposure = camera.exposure();
+
+    let world = bvh(world, exposure);
+
+    (camera, world, background)
+}
 
 fn main() {
-    eprintln!(
-        "{:?}",
-        (0..10)
-            .collect::<Vec<u32>>()
-            .chunks(4)
-            .collect::<Vec<&[u32]>>()
-    );
-
-    let exposure = 0f32..1.;
-
-    // let (camera, world, background) = sphere_scene(exposure.clone());
-    // let (camera, world) = checker_scene(exposure.clone());
-    // let (camera, world, background) = perlin_scene(exposure.clone());
-    // let (camera, world, background) = earth_scene(exposure.clone());
-    // let (camera, world, background) = earth_lights_scene(exposure.clone());
-    // let (camera, world, background) = triangle_scene(exposure.clone());
-    let (camera, world, background) = bunny_scene(exposure.clone());
-    // let (camera, world, background) = constant_medium_scene(exposure.clone());
-    // let (camera, world, background) = cornell_box_scene(exposure.clone());
-
-    // let mut rng = rand::rngs::StdRng::seed_from_u64(0xAA33EBC);
-    // let image = Image::cast(WIDTH, HEIGHT, 10, &camera, &world[..], &mut rng);
-
-    let world = Bvh::new(world, exposure.clone());
-
-    // let image = Image::par_cast(WIDTH, HEIGHT, SAMPLES, &camera, background, world);
-    // image.print_ppm();
-
-    Preview::run(WIDTH, HEIGHT, Arc::new(world), camera, background);
+    let opts = Opts::from_args();
+
+    if opts.list_scenes {
+        for (name, _) in PRESETS {
+            println!("{}", name);
+        }
+        return;
+    }
+
+    if opts.first_frame < 1 {
+        eprintln!("frames are numbered from 1");
+        std::process::exit(1);
+    }
+
+    let nx = opts.width;
+    let ny = opts.height.unwrap_or((nx as f32 / 1.5) as usize);
+    let aspect_ratio = nx as f32 / ny as f32;
+
+    if nx < 2 || ny < 2 {
+        eprintln!(
+            "images must be at least 2 pixels wide and high, got {}x{}",
+            nx, ny
+        );
+        std::process::exit(1);
+    }
+
+    if opts.shutter_close < opts.shutter_open {
+        eprintln!("the shutter must close after it opens");
+        std::process::exit(1);
+    }
+
+    // Passes are written next to the output, there's nowhere to put them on stdout
+    if !opts.passes.is_empty() && opts.output.is_none() {
+        eprintln!("--pass needs an output file, see --output");
+        std::process::exit(1);
+    }
+
+    let timeline = opts.frames.map(|frames| Timeline {
+        frames,
+        fps: opts.fps,
+        shutter_angle: opts.shutter_angle,
+    });
+
+    let config = PathConfig {
+        min_depth: opts.min_depth,
+        max_depth: opts.max_depth,
+        max_diffuse: opts.max_diffuse,
+        max_specular: opts.max_specular,
+        max_volume: opts.max_volume,
+    };
+
+    let tone_map = ToneMap {
+        operator: opts.tonemap,
+        exposure: opts.ev,
+        white: opts.white,
+        working_space: working_space(&opts),
+    };
+
+    if opts.preview {
+        let exposure = match timeline {
+            Some(timeline) => timeline.exposure(opts.first_frame),
+            None => opts.shutter_open..opts.shutter_close,
+        };
+        let (camera, world, background) =
+            load(&opts, aspect_ratio, exposure, &mut Assets::default());
+
+        Preview::run(
+            nx,
+            ny,
+            Arc::new(world),
+            camera,
+            background,
+            config,
+            tone_map,
+        );
+        return;
+    }
+
+    let seed = opts.seed.unwrap_or_else(|| thread_rng().gen());
+    eprintln!("seed: {}", seed);
+
+    let mut assets = Assets::default();
+
+    let mut render = |exposure: Range<f32>, seed: u64| {
+        // Only the files are kept between frames. Animated values are baked into the camera
+        // and materials at the frame's time, and moving objects are bounded over its exposure,
+        // so the rest of the scene and its BVHs are built again
+        let (camera, world, background) = load(&opts, aspect_ratio, exposure, &mut assets);
+
+        Image::par_cast_layers(
+            nx,
+            ny,
+            opts.samples,
+            seed,
+            &camera,
+            background,
+            world,
+            &config,
+            &opts.passes,
+        )
+    };
+
+    let save = |layers: Layers, path: &Path| {
+        layers.save(path, &tone_map).unwrap_or_else(|e| {
+            eprintln!("{}: {}", path.display(), e);
+            std::process::exit(1);
+        })
+    };
+
+    match (timeline, &opts.output) {
+        (None, Some(path)) => save(render(opts.shutter_open..opts.shutter_close, seed), path),
+        (None, None) => render(opts.shutter_open..opts.shutter_close, seed)
+            .beauty
+            .print_ppm(&tone_map),
+        (Some(timeline), Some(path)) => {
+            for frame in opts.first_frame..=timeline.frames {
+                let frame_path = Timeline::frame_path(path, frame);
+
+                if opts.resume && frame_path.exists() {
+                    eprintln!("frame {} / {}: skipped", frame, timeline.frames);
+                    continue;
+                }
+
+                eprintln!("frame {} / {}", frame, timeline.frames);
+
+                // Different noise in every frame, but the same for a frame that is rendered again
+                let layers = render(
+                    timeline.exposure(frame),
+                    seed.wrapping_add((frame as u64) << 32),
+                );
+                save(layers, &frame_path);
+            }
+        }
+        (Some(_), None) => {
+            eprintln!("animations need an output path");
+            std::process::exit(1);
+        }
+    }
 }
@@ -1,9 +1,10 @@
-use crate::math::Vec3;
+use crate::math::{Onb, Vec3};
 use crate::primitive::HitRecord;
-use crate::ray::Ray;
+use crate::ray::{Ray, RayDifferential};
 
 use crate::texture::Texture;
 use rand::Rng;
+use std::f32::consts::PI;
 
 #[derive(Clone)]
 pub enum Material {
@@ -29,6 +30,17 @@ pub enum Material {
     },
 }
 
+/// Direction sampled by `Material::sample`.
+pub struct BsdfSample {
+    /// Unit direction of the scattered ray.
+    pub wi: Vec3,
+    /// `eval(wi, wo) / pdf`, or the attenuation of a specular sample.
+    pub weight: Vec3,
+    /// Solid angle density of `wi`, zero for specular samples.
+    pub pdf: f32,
+    pub specular: bool,
+}
+
 impl Material {
     pub fn scatter(
         &self,
@@ -36,25 +48,77 @@ impl Material {
         hit: &HitRecord,
         rng: &mut impl Rng,
     ) -> Option<(Vec3, Ray)> {
+        self.sample(-ray_in.dir.unit(), hit, rng)
+            .map(|s| (s.weight, self.scattered_ray(ray_in, hit, &s)))
+    }
+
+    /// Ray leaving `hit` in the direction of `sample`. Specular bounces carry the
+    /// differentials of `ray_in` over, bending each offset ray about the normal where it hits
+    /// to first order, so that curved mirrors and lenses spread or focus the footprint. They're
+    /// dropped at diffuse bounces, where the footprint spreads too widely to be worth tracking.
+    pub fn scattered_ray(&self, ray_in: &Ray, hit: &HitRecord, sample: &BsdfSample) -> Ray {
+        Ray {
+            origin: hit.point(),
+            dir: sample.wi,
+            t: ray_in.t,
+            differential: if sample.specular {
+                self.scattered_differential(ray_in, hit, sample.wi)
+            } else {
+                None
+            },
+        }
+    }
+
+    fn scattered_differential(
+        &self,
+        ray_in: &Ray,
+        hit: &HitRecord,
+        wi: Vec3,
+    ) -> Option<RayDifferential> {
+        let d = ray_in.differential?;
+        let n = hit.normal();
+
+        // Ideal reflection or refraction of an incoming direction about `m`, whichever
+        // produced `wi`
+        let bend = |dir: Vec3, m: Vec3| match self {
+            Material::Dielectric { ior, .. } if Vec3::dot(wi, n) < 0. => {
+                let ir = if hit.front_face() { 1. / *ior } else { *ior };
+                Vec3::refract(dir, m, ir)
+            }
+            Material::Metal { .. } | Material::Dielectric { .. } => {
+                Some(Vec3::reflect(dir.unit(), m))
+            }
+            _ => None,
+        };
+
+        // Offsets from the ideal direction, e.g. by fuzz, apply to the offset rays too
+        let base = bend(ray_in.dir, n)?;
+        let (nx, ny) = ((n + hit.dndx()).unit(), (n + hit.dndy()).unit());
+
+        Some(RayDifferential {
+            rx_origin: hit.point() + hit.dpdx(),
+            rx_dir: wi + bend(d.rx_dir, nx)? - base,
+            ry_origin: hit.point() + hit.dpdy(),
+            ry_dir: wi + bend(d.ry_dir, ny)? - base,
+        })
+    }
+
+    /// Samples a scattered direction for light leaving towards `wo`, the unit direction
+    /// back along the incoming ray.
+    pub fn sample(&self, wo: Vec3, hit: &HitRecord, rng: &mut impl Rng) -> Option<BsdfSample> {
         match self {
             Material::Lambertian { albedo } => {
-                let mut dir = hit.normal() + Vec3::rand_in_unit_sphere(rng);
+                let wi = Onb::from_w(hit.normal()).local(Vec3::rand_cosine_direction(rng));
 
-                if dir.near_zero() {
-                    dir = hit.normal();
-                }
-
-                Some((
-                    albedo(hit.uv(), hit.point()),
-                    Ray {
-                        origin: hit.point(),
-                        dir,
-                        t: ray_in.t,
-                    },
-                ))
+                Some(BsdfSample {
+                    wi,
+                    weight: albedo(&hit.tex_coord()),
+                    pdf: self.pdf(wi, wo, hit),
+                    specular: false,
+                })
             }
             Material::Metal { albedo, fuzz } => {
-                let reflected = Vec3::reflect(ray_in.dir.unit(), hit.normal());
+                let reflected = Vec3::reflect(-wo, hit.normal());
                 let mut dir = reflected + *fuzz * Vec3::rand_in_unit_sphere(rng);
 
                 if dir.near_zero() {
@@ -64,20 +128,18 @@ impl Material {
                 if Vec3::dot(hit.normal(), dir) < 0. {
                     None
                 } else {
-                    Some((
-                        albedo(hit.uv(), hit.point()),
-                        Ray {
-                            origin: hit.point(),
-                            dir: dir.unit(),
-                            t: ray_in.t,
-                        },
-                    ))
+                    Some(BsdfSample {
+                        wi: dir.unit(),
+                        weight: albedo(&hit.tex_coord()),
+                        pdf: 0.,
+                        specular: true,
+                    })
                 }
             }
             Material::Dielectric { fuzz, albedo, ior } => {
                 let ir = if hit.front_face() { 1. / *ior } else { *ior };
 
-                let dir = ray_in.dir.unit();
+                let dir = -wo;
                 let n = hit.normal().unit();
 
                 let cos_theta = Vec3::dot(-dir, n);
@@ -91,30 +153,68 @@ impl Material {
                     r = hit.normal();
                 }
 
-                Some((
-                    albedo(hit.uv(), hit.point()),
-                    Ray {
-                        origin: hit.point(),
-                        dir: r.unit(),
-                        t: ray_in.t,
-                    },
-                ))
+                Some(BsdfSample {
+                    wi: r.unit(),
+                    weight: albedo(&hit.tex_coord()),
+                    pdf: 0.,
+                    specular: true,
+                })
             }
-            Material::Isotropic { albedo } => Some((
-                *albedo,
-                Ray {
-                    origin: hit.point(),
-                    dir: Vec3::rand_in_unit_sphere(rng),
-                    t: ray_in.t,
-                },
-            )),
+            Material::Isotropic { albedo } => Some(BsdfSample {
+                wi: Vec3::rand_in_unit_sphere(rng).unit(),
+                weight: *albedo,
+                pdf: 1. / (4. * PI),
+                specular: false,
+            }),
             _ => None,
         }
     }
 
+    /// Scattered radiance towards `wo` per unit radiance arriving from `wi`, i.e. the BSDF
+    /// times the cosine term. Zero for specular materials.
+    pub fn eval(&self, wi: Vec3, wo: Vec3, hit: &HitRecord) -> Vec3 {
+        match self {
+            Material::Lambertian { albedo } => albedo(&hit.tex_coord()) * self.pdf(wi, wo, hit),
+            Material::Isotropic { albedo } => *albedo / (4. * PI),
+            _ => Vec3::from(0.),
+        }
+    }
+
+    /// Solid angle density with which `sample` produces `wi`. Zero for specular materials.
+    pub fn pdf(&self, wi: Vec3, _wo: Vec3, hit: &HitRecord) -> f32 {
+        match self {
+            Material::Lambertian { .. } => Vec3::dot(hit.normal(), wi.unit()).max(0.) / PI,
+            Material::Isotropic { .. } => 1. / (4. * PI),
+            _ => 0.,
+        }
+    }
+
+    pub fn is_emissive(&self) -> bool {
+        matches!(self, Material::DiffuseLight { .. })
+    }
+
+    /// Whether scattered directions come from a (near) delta distribution that can't be
+    /// evaluated for arbitrary directions. Fuzzy metals and dielectrics count as specular
+    /// too, as their perturbed directions don't have a tractable density.
+    pub fn is_specular(&self) -> bool {
+        matches!(self, Material::Metal { .. } | Material::Dielectric { .. })
+    }
+
+    /// Surface color at `hit`, without lighting.
+    pub fn albedo(&self, hit: &HitRecord) -> Vec3 {
+        match self {
+            Material::Empty => Vec3::from(0.),
+            Material::Lambertian { albedo }
+            | Material::Metal { albedo, .. }
+            | Material::Dielectric { albedo, .. } => albedo(&hit.tex_coord()),
+            Material::DiffuseLight { emit, .. } => emit(&hit.tex_coord()),
+            Material::Isotropic { albedo } => *albedo,
+        }
+    }
+
     pub fn emitted(&self, hit: &HitRecord) -> Vec3 {
         match self {
-            Material::DiffuseLight { emit, intensity } => *intensity * emit(hit.uv(), hit.point()),
+            Material::DiffuseLight { emit, intensity } => *intensity * emit(&hit.tex_coord()),
             _ => Vec3::new(0., 0., 0.),
         }
     }
@@ -125,3 +225,145 @@ fn schlick_reflectance(c: f32, ref_idx: f32) -> f32 {
     let r0 = r * r;
     r0 + (1. - r0) * (1. - c).powi(5)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::primitive::{Hittable, Sphere};
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    fn assert_close(a: Vec3, b: Vec3) {
+        assert!((a - b).len() < 1e-5, "{:?} != {:?}", a, b);
+    }
+
+    /// Compares the offset ray leaving a sphere of `material` with the ray bent where the
+    /// offset ray really hits, `bend` giving the ideal direction about a normal.
+    fn check_offset_ray(material: Material, bend: impl Fn(Vec3, Vec3) -> Vec3) {
+        let sphere = Sphere {
+            center: Vec3::from(0.),
+            radius: 2.,
+            material,
+        };
+        let (origin, offset, dir) = (
+            Vec3::new(0.3, 0.2, 5.),
+            Vec3::new(1e-3, 0., 0.),
+            Vec3::new(0., 0., -1.),
+        );
+        let ray = |origin: Vec3| Ray {
+            origin,
+            dir,
+            t: 0.,
+            differential: Some(RayDifferential {
+                rx_origin: origin + offset,
+                rx_dir: dir,
+                ry_origin: origin,
+                ry_dir: dir,
+            }),
+        };
+        let trace = |ray: &Ray| sphere.hit(ray, 0.001..f32::INFINITY, &mut || 0.5).unwrap();
+
+        let ray_in = ray(origin);
+        let mut hit = trace(&ray_in);
+        hit.compute_differentials(&ray_in);
+
+        let sample = BsdfSample {
+            wi: bend(dir, hit.normal()),
+            weight: Vec3::from(1.),
+            pdf: 0.,
+            specular: true,
+        };
+        let d = sphere
+            .material
+            .scattered_ray(&ray_in, &hit, &sample)
+            .differential
+            .unwrap();
+        let exact = trace(&ray(origin + offset));
+
+        assert_close(d.rx_origin, exact.point());
+        assert_close(d.rx_dir.unit(), bend(dir, exact.normal()).unit());
+    }
+
+    #[test]
+    fn curved_mirror_spreads_offset_rays() {
+        let metal = Material::Metal {
+            albedo: Texture::from(Vec3::from(1.)),
+            fuzz: 0.,
+        };
+
+        check_offset_ray(metal, Vec3::reflect);
+    }
+
+    #[test]
+    fn curved_glass_bends_offset_rays() {
+        let glass = Material::Dielectric {
+            albedo: Texture::from(Vec3::from(1.)),
+            fuzz: 0.,
+            ior: 1.5,
+        };
+
+        check_offset_ray(glass, |d, n| Vec3::refract(d, n, 1. / 1.5).unwrap());
+    }
+
+    /// Hit on a surface facing +z at the origin.
+    fn surface_hit(material: &Material) -> HitRecord<'_> {
+        let ray = Ray {
+            origin: Vec3::new(0., 0., 1.),
+            dir: Vec3::new(0., 0., -1.),
+            t: 0.,
+            differential: None,
+        };
+
+        HitRecord::new(&ray, 1., Vec3::new(0., 0., 1.), Vec3::from(0.), material)
+    }
+
+    fn diffuse() -> [Material; 2] {
+        [
+            Material::Lambertian {
+                albedo: Texture::from(Vec3::new(0.8, 0.5, 0.2)),
+            },
+            Material::Isotropic {
+                albedo: Vec3::new(0.8, 0.5, 0.2),
+            },
+        ]
+    }
+
+    #[test]
+    fn pdfs_integrate_to_one() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let n = 200_000;
+
+        for material in diffuse() {
+            let hit = surface_hit(&material);
+            let wo = Vec3::new(0.3, 0., 1.).unit();
+
+            // Uniform directions over the sphere have density 1 / 4π
+            let mean = (0..n)
+                .map(|_| material.pdf(Vec3::rand_in_unit_sphere(&mut rng).unit(), wo, &hit))
+                .sum::<f32>()
+                / n as f32;
+
+            assert!((4. * PI * mean - 1.).abs() < 0.01, "{}", 4. * PI * mean);
+        }
+    }
+
+    #[test]
+    fn sample_weights_are_eval_over_pdf() {
+        let mut rng = StdRng::seed_from_u64(1);
+
+        for material in diffuse() {
+            let hit = surface_hit(&material);
+            let wo = Vec3::new(0.3, 0., 1.).unit();
+
+            for _ in 0..100 {
+                let sample = material.sample(wo, &hit, &mut rng).unwrap();
+
+                assert!((sample.pdf - material.pdf(sample.wi, wo, &hit)).abs() < 1e-4);
+                assert_close(
+                    sample.weight,
+                    material.eval(sample.wi, wo, &hit) / sample.pdf,
+                );
+            }
+        }
+    }
+}
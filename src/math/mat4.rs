@@ -42,28 +42,64 @@ impl Mat4 {
             - d * (e * j * o + f * k * m + g * i * n - e * k * n - f * i * o - g * j * m)
     }
 
+    #[inline]
+    pub fn transpose(&self) -> Self {
+        let mut data = [0.; 16];
+        for i in 0..4 {
+            for j in 0..4 {
+                data[4 * i + j] = self.0[4 * j + i];
+            }
+        }
+        Mat4(data)
+    }
+
+    /// Inverse by cofactor expansion, `None` if the matrix is singular.
     #[inline]
     pub fn inv(&self) -> Option<Self> {
-        let det = self.det();
+        let [a00, a01, a02, a03, a10, a11, a12, a13, a20, a21, a22, a23, a30, a31, a32, a33] =
+            self.0;
 
-        if det < 1e-10 {
-            return None;
-        }
+        // 2x2 minors of the upper and lower two rows
+        let s0 = a00 * a11 - a10 * a01;
+        let s1 = a00 * a12 - a10 * a02;
+        let s2 = a00 * a13 - a10 * a03;
+        let s3 = a01 * a12 - a11 * a02;
+        let s4 = a01 * a13 - a11 * a03;
+        let s5 = a02 * a13 - a12 * a03;
+
+        let c0 = a20 * a31 - a30 * a21;
+        let c1 = a20 * a32 - a30 * a22;
+        let c2 = a20 * a33 - a30 * a23;
+        let c3 = a21 * a32 - a31 * a22;
+        let c4 = a21 * a33 - a31 * a23;
+        let c5 = a22 * a33 - a32 * a23;
 
-        let a = *self;
-        let a2 = a * a;
-        let a3 = a * a2;
+        let det = s0 * c5 - s1 * c4 + s2 * c3 + s3 * c2 - s4 * c1 + s5 * c0;
 
-        let tr_a = a.tr();
-        let tr_a2 = a2.tr();
-        let tr_a3 = a3.tr();
+        if det.abs() < 1e-12 {
+            return None;
+        }
 
         Some(
             1. / det
-                * (1. / 6. * (tr_a * tr_a * tr_a - 3. * tr_a * tr_a2 + 2. * tr_a3) * Mat4::eye()
-                    - 1. / 2. * (tr_a * tr_a - tr_a2) * a
-                    + a2 * tr_a
-                    - a3),
+                * Mat4([
+                    a11 * c5 - a12 * c4 + a13 * c3,
+                    -a01 * c5 + a02 * c4 - a03 * c3,
+                    a31 * s5 - a32 * s4 + a33 * s3,
+                    -a21 * s5 + a22 * s4 - a23 * s3,
+                    -a10 * c5 + a12 * c2 - a13 * c1,
+                    a00 * c5 - a02 * c2 + a03 * c1,
+                    -a30 * s5 + a32 * s2 - a33 * s1,
+                    a20 * s5 - a22 * s2 + a23 * s1,
+                    a10 * c4 - a11 * c2 + a13 * c0,
+                    -a00 * c4 + a01 * c2 - a03 * c0,
+                    a30 * s4 - a31 * s2 + a33 * s0,
+                    -a20 * s4 + a21 * s2 - a23 * s0,
+                    -a10 * c3 + a11 * c1 - a12 * c0,
+                    a00 * c3 - a01 * c1 + a02 * c0,
+                    -a30 * s3 + a31 * s1 - a32 * s0,
+                    a20 * s3 - a21 * s1 + a22 * s0,
+                ]),
         )
     }
 }
@@ -87,3 +123,60 @@ impl ZipMap for Mat4 {
         Mat4(data)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(a: &Mat4, b: &Mat4) {
+        for (x, y) in a.0.iter().zip(b.0.iter()) {
+            assert!((x - y).abs() < 1e-5, "{:?} != {:?}", a, b);
+        }
+    }
+
+    #[test]
+    fn inverse_of_known_matrix() {
+        // Translation by (1, 2, 3) after scaling by (2, 4, -1)
+        let m = Mat4([
+            2., 0., 0., 1., 0., 4., 0., 2., 0., 0., -1., 3., 0., 0., 0., 1.,
+        ]);
+        let inverse = Mat4([
+            0.5, 0., 0., -0.5, 0., 0.25, 0., -0.5, 0., 0., -1., 3., 0., 0., 0., 1.,
+        ]);
+
+        assert_close(&m.inv().unwrap(), &inverse);
+    }
+
+    #[test]
+    fn inverse_of_general_matrix() {
+        let m = Mat4([
+            1., 2., 0., 1., 0., 1., 3., -2., 4., 0., 1., 0., 2., 1., 0., 1.,
+        ]);
+
+        assert_close(&(m * m.inv().unwrap()), &Mat4::eye());
+        assert_close(&(m.inv().unwrap() * m), &Mat4::eye());
+    }
+
+    #[test]
+    fn singular_matrix_has_no_inverse() {
+        let m = Mat4([
+            1., 2., 3., 4., 2., 4., 6., 8., 0., 1., 0., 1., 1., 0., 0., 1.,
+        ]);
+
+        assert!(m.inv().is_none());
+        assert!(Mat4([0.; 16]).inv().is_none());
+    }
+
+    #[test]
+    fn transpose_swaps_rows_and_columns() {
+        let m = Mat4([
+            0., 1., 2., 3., 4., 5., 6., 7., 8., 9., 10., 11., 12., 13., 14., 15.,
+        ]);
+        let t = m.transpose();
+
+        assert_eq!(t.0[1], 4.);
+        assert_eq!(t.0[4], 1.);
+        assert_eq!(t.0[11], 14.);
+        assert_close(&t.transpose(), &m);
+    }
+}
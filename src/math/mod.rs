@@ -1,4 +1,5 @@
 use image::Rgba;
+use serde::Deserialize;
 use std::ops::Index;
 
 mod add;
@@ -8,6 +9,8 @@ mod neg;
 mod sub;
 
 mod mat4;
+mod onb;
+mod quat;
 mod vec3;
 mod vec4;
 
@@ -17,9 +20,25 @@ pub struct Mat4(pub [f32; 16]);
 #[derive(Debug, Copy, Clone)]
 pub struct Vec4([f32; 4]);
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, Deserialize)]
+#[serde(from = "(f32, f32, f32)")]
 pub struct Vec3([f32; 3]);
 
+/// Rotation quaternion `w + xi + yj + zk`, with the imaginary part in `v`.
+#[derive(Debug, Copy, Clone)]
+pub struct Quat {
+    w: f32,
+    v: Vec3,
+}
+
+/// Orthonormal basis.
+#[derive(Debug, Copy, Clone)]
+pub struct Onb {
+    u: Vec3,
+    v: Vec3,
+    w: Vec3,
+}
+
 pub trait ZipMap {
     fn zip_map(&self, other: &Self, f: impl Fn(f32, f32) -> f32) -> Self;
     fn map(&self, f: impl Fn(f32) -> f32) -> Self;
@@ -0,0 +1,39 @@
+use crate::math::{Onb, Vec3};
+
+impl Onb {
+    /// Basis with `w` along `n`, which doesn't need to be normalized.
+    #[inline]
+    pub fn from_w(n: Vec3) -> Self {
+        let w = n.unit();
+        let a = if w.x().abs() > 0.9 {
+            Vec3::new(0., 1., 0.)
+        } else {
+            Vec3::new(1., 0., 0.)
+        };
+        let v = Vec3::cross(w, a).unit();
+        let u = Vec3::cross(w, v);
+
+        Onb { u, v, w }
+    }
+
+    #[inline]
+    pub fn u(&self) -> Vec3 {
+        self.u
+    }
+
+    #[inline]
+    pub fn v(&self) -> Vec3 {
+        self.v
+    }
+
+    #[inline]
+    pub fn w(&self) -> Vec3 {
+        self.w
+    }
+
+    /// Transforms `a` from the local frame to world space.
+    #[inline]
+    pub fn local(&self, a: Vec3) -> Vec3 {
+        a.x() * self.u + a.y() * self.v + a.z() * self.w
+    }
+}
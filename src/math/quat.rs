@@ -0,0 +1,234 @@
+use crate::math::{Mat4, Quat, Vec3};
+use std::ops::Mul;
+
+impl Quat {
+    #[inline]
+    pub fn new(w: f32, x: f32, y: f32, z: f32) -> Self {
+        Quat {
+            w,
+            v: Vec3::new(x, y, z),
+        }
+    }
+
+    #[inline]
+    pub fn identity() -> Self {
+        Quat::new(1., 0., 0., 0.)
+    }
+
+    /// Rotation by `angle` radians counterclockwise around `axis`, which doesn't need to be
+    /// normalized.
+    #[inline]
+    pub fn from_axis_angle(axis: Vec3, angle: f32) -> Self {
+        let (s, c) = (0.5 * angle).sin_cos();
+
+        Quat {
+            w: c,
+            v: s * axis.unit(),
+        }
+    }
+
+    /// Quaternion of the rotation in the upper left 3x3 block of `m`, which must be
+    /// orthonormal.
+    pub fn from_mat4(m: &Mat4) -> Self {
+        let m = |i: usize, j: usize| m.0[4 * i + j];
+        let trace = m(0, 0) + m(1, 1) + m(2, 2);
+
+        let q = if trace > 0. {
+            let s = 0.5 / (trace + 1.).sqrt();
+            Quat::new(
+                0.25 / s,
+                (m(2, 1) - m(1, 2)) * s,
+                (m(0, 2) - m(2, 0)) * s,
+                (m(1, 0) - m(0, 1)) * s,
+            )
+        } else if m(0, 0) > m(1, 1) && m(0, 0) > m(2, 2) {
+            let s = 2. * (1. + m(0, 0) - m(1, 1) - m(2, 2)).sqrt();
+            Quat::new(
+                (m(2, 1) - m(1, 2)) / s,
+                0.25 * s,
+                (m(0, 1) + m(1, 0)) / s,
+                (m(0, 2) + m(2, 0)) / s,
+            )
+        } else if m(1, 1) > m(2, 2) {
+            let s = 2. * (1. + m(1, 1) - m(0, 0) - m(2, 2)).sqrt();
+            Quat::new(
+                (m(0, 2) - m(2, 0)) / s,
+                (m(0, 1) + m(1, 0)) / s,
+                0.25 * s,
+                (m(1, 2) + m(2, 1)) / s,
+            )
+        } else {
+            let s = 2. * (1. + m(2, 2) - m(0, 0) - m(1, 1)).sqrt();
+            Quat::new(
+                (m(1, 0) - m(0, 1)) / s,
+                (m(0, 2) + m(2, 0)) / s,
+                (m(1, 2) + m(2, 1)) / s,
+                0.25 * s,
+            )
+        };
+
+        q.unit()
+    }
+
+    /// Spherical linear interpolation between unit quaternions along the shorter arc.
+    pub fn slerp(a: Quat, b: Quat, t: f32) -> Quat {
+        let mut cos = Quat::dot(a, b);
+        let b = if cos < 0. {
+            cos = -cos;
+            Quat { w: -b.w, v: -b.v }
+        } else {
+            b
+        };
+
+        let (wa, wb) = if cos > 0.9995 {
+            // Nearly parallel, fall back to normalized linear interpolation
+            (1. - t, t)
+        } else {
+            let theta = cos.acos();
+            let sin = theta.sin();
+            (((1. - t) * theta).sin() / sin, (t * theta).sin() / sin)
+        };
+
+        Quat {
+            w: wa * a.w + wb * b.w,
+            v: wa * a.v + wb * b.v,
+        }
+        .unit()
+    }
+
+    #[inline]
+    pub fn w(&self) -> f32 {
+        self.w
+    }
+
+    #[inline]
+    pub fn v(&self) -> Vec3 {
+        self.v
+    }
+
+    #[inline]
+    pub fn dot(a: Quat, b: Quat) -> f32 {
+        a.w * b.w + Vec3::dot(a.v, b.v)
+    }
+
+    #[inline]
+    pub fn unit(&self) -> Self {
+        let len = Quat::dot(*self, *self).sqrt();
+
+        Quat {
+            w: self.w / len,
+            v: self.v / len,
+        }
+    }
+
+    #[inline]
+    pub fn conjugate(&self) -> Self {
+        Quat {
+            w: self.w,
+            v: -self.v,
+        }
+    }
+
+    /// Rotates `a` by this unit quaternion.
+    #[inline]
+    pub fn rotate(&self, a: Vec3) -> Vec3 {
+        let t = 2. * Vec3::cross(self.v, a);
+        a + self.w * t + Vec3::cross(self.v, t)
+    }
+
+    /// Rotation matrix of this unit quaternion.
+    pub fn to_mat4(&self) -> Mat4 {
+        let (w, x, y, z) = (self.w, self.v.x(), self.v.y(), self.v.z());
+
+        Mat4([
+            1. - 2. * (y * y + z * z),
+            2. * (x * y - w * z),
+            2. * (x * z + w * y),
+            0.,
+            2. * (x * y + w * z),
+            1. - 2. * (x * x + z * z),
+            2. * (y * z - w * x),
+            0.,
+            2. * (x * z - w * y),
+            2. * (y * z + w * x),
+            1. - 2. * (x * x + y * y),
+            0.,
+            0.,
+            0.,
+            0.,
+            1.,
+        ])
+    }
+}
+
+impl Mul for Quat {
+    type Output = Quat;
+
+    /// Composes the rotations, applying `rhs` first.
+    fn mul(self, rhs: Self) -> Self::Output {
+        Quat {
+            w: self.w * rhs.w - Vec3::dot(self.v, rhs.v),
+            v: self.w * rhs.v + rhs.w * self.v + Vec3::cross(self.v, rhs.v),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(a: Quat, b: Quat) {
+        // q and -q are the same rotation
+        assert!(Quat::dot(a, b).abs() > 1. - 1e-5, "{:?} != {:?}", a, b);
+    }
+
+    #[test]
+    fn slerp_hits_endpoints() {
+        let a = Quat::from_axis_angle(Vec3::new(0., 1., 0.), 0.3);
+        let b = Quat::from_axis_angle(Vec3::new(1., 0., 1.), 2.);
+
+        assert_close(Quat::slerp(a, b, 0.), a);
+        assert_close(Quat::slerp(a, b, 1.), b);
+    }
+
+    #[test]
+    fn slerp_halves_angle() {
+        let axis = Vec3::new(0., 0., 1.);
+        let a = Quat::identity();
+        let b = Quat::from_axis_angle(axis, 2.);
+
+        assert_close(Quat::slerp(a, b, 0.5), Quat::from_axis_angle(axis, 1.));
+    }
+
+    #[test]
+    fn slerp_takes_shorter_arc() {
+        let a = Quat::identity();
+        let b = Quat::from_axis_angle(Vec3::new(1., 0., 0.), 0.5);
+        let negated = Quat::new(-b.w, -b.v.x(), -b.v.y(), -b.v.z());
+
+        assert_close(Quat::slerp(a, negated, 0.5), Quat::slerp(a, b, 0.5));
+    }
+
+    #[test]
+    fn from_mat4_inverts_to_mat4() {
+        // Angles that exercise each branch of the conversion
+        for (axis, angle) in &[
+            (Vec3::new(0., 1., 0.), 0.5),
+            (Vec3::new(1., 0., 0.), 3.),
+            (Vec3::new(0., 1., 0.), 3.),
+            (Vec3::new(0., 0., 1.), 3.),
+            (Vec3::new(1., 2., 3.), 1.),
+        ] {
+            let q = Quat::from_axis_angle(*axis, *angle);
+            assert_close(Quat::from_mat4(&q.to_mat4()), q);
+        }
+    }
+
+    #[test]
+    fn rotate_matches_matrix() {
+        let q = Quat::from_axis_angle(Vec3::new(0., 0., 1.), std::f32::consts::FRAC_PI_2);
+        let r = q.rotate(Vec3::new(1., 0., 0.));
+
+        assert!((r - Vec3::new(0., 1., 0.)).len() < 1e-6);
+    }
+}
@@ -127,6 +127,17 @@ impl Vec3 {
         }
     }
 
+    /// Cosine-weighted direction on the hemisphere around +z, with density `z / PI`.
+    #[inline]
+    pub fn rand_cosine_direction(rng: &mut impl Rng) -> Vec3 {
+        let r1 = rng.gen::<f32>();
+        let r2 = rng.gen::<f32>();
+        let phi = 2. * std::f32::consts::PI * r1;
+        let r = r2.sqrt();
+
+        Vec3::new(phi.cos() * r, phi.sin() * r, (1. - r2).max(0.).sqrt())
+    }
+
     #[inline]
     pub fn rand(range: Range<f32>, rng: &mut impl Rng) -> Vec3 {
         Vec3::new(
@@ -166,6 +177,12 @@ impl From<f32> for Vec3 {
     }
 }
 
+impl From<(f32, f32, f32)> for Vec3 {
+    fn from((x, y, z): (f32, f32, f32)) -> Self {
+        Vec3::new(x, y, z)
+    }
+}
+
 impl std::iter::Sum for Vec3 {
     #[inline]
     fn sum<I>(iter: I) -> Self
@@ -175,3 +192,28 @@ impl std::iter::Sum for Vec3 {
         iter.fold(Vec3::from(0.), Add::add)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    #[test]
+    fn cosine_directions_have_mean_cosine_two_thirds() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let n = 200_000;
+
+        let sum = (0..n)
+            .map(|_| {
+                let v = Vec3::rand_cosine_direction(&mut rng);
+                assert!((v.len() - 1.).abs() < 1e-4 && v.z() >= 0.);
+                v
+            })
+            .sum::<Vec3>()
+            / n as f32;
+
+        assert!((sum.z() - 2. / 3.).abs() < 3e-3, "{}", sum.z());
+        assert!(sum.x().abs() < 3e-3 && sum.y().abs() < 3e-3, "{:?}", sum);
+    }
+}
@@ -1,14 +1,19 @@
+use crate::assets::Assets;
 use crate::material::Material;
-use crate::math::{Vec3, Vec4};
+use crate::math::Vec3;
 use crate::primitive::Triangle;
 use crate::primitive::{Hittable, Transform};
 
 pub struct Mesh;
 
 impl Mesh {
-    pub fn load(path: String, transform: &Transform, material: Material) -> Vec<Box<dyn Hittable>> {
-        let (models, _materials) =
-            tobj::load_obj(&path, true).expect(format!("Failed to open \"{}\".", &path).as_str());
+    pub fn load(
+        path: String,
+        transform: &Transform,
+        material: Material,
+        assets: &mut Assets,
+    ) -> Result<Vec<Box<dyn Hittable>>, tobj::LoadError> {
+        let models = assets.models(&path)?;
 
         let mut objs: Vec<Box<dyn Hittable>> = vec![];
 
@@ -35,35 +40,19 @@ impl Mesh {
                 );
                 let v = &m.mesh.positions;
 
-                let v1 = {
-                    let v = transform.apply(Vec4::new(v[3 * i], v[3 * i + 1], v[3 * i + 2], 1.));
-                    v.xyz() / v.w()
-                };
-                let v2 = {
-                    let v = transform.apply(Vec4::new(v[3 * j], v[3 * j + 1], v[3 * j + 2], 1.));
-                    v.xyz() / v.w()
-                };
-                let v3 = {
-                    let v = transform.apply(Vec4::new(v[3 * k], v[3 * k + 1], v[3 * k + 2], 1.));
-                    v.xyz() / v.w()
-                };
+                let v1 = transform.point(Vec3::new(v[3 * i], v[3 * i + 1], v[3 * i + 2]));
+                let v2 = transform.point(Vec3::new(v[3 * j], v[3 * j + 1], v[3 * j + 2]));
+                let v3 = transform.point(Vec3::new(v[3 * k], v[3 * k + 1], v[3 * k + 2]));
 
                 let normals = if !m.mesh.normals.is_empty() {
                     let n = &m.mesh.normals;
-                    (
+                    let normal = |i: usize| {
                         transform
-                            .apply(Vec4::new(n[3 * i], n[3 * i + 1], n[3 * i + 2], 0.))
-                            .xyz()
-                            .unit(),
-                        transform
-                            .apply(Vec4::new(n[3 * j], n[3 * j + 1], n[3 * j + 2], 0.))
-                            .xyz()
-                            .unit(),
-                        transform
-                            .apply(Vec4::new(n[3 * k], n[3 * k + 1], n[3 * k + 2], 0.))
-                            .xyz()
-                            .unit(),
-                    )
+                            .normal(Vec3::new(n[3 * i], n[3 * i + 1], n[3 * i + 2]))
+                            .unit()
+                    };
+
+                    (normal(i), normal(j), normal(k))
                 } else {
                     let n = Vec3::cross(v3 - v1, v2 - v1);
                     (n, n, n)
@@ -81,6 +70,6 @@ impl Mesh {
 
         eprintln!("loaded {} tris: {}", path, objs.len());
 
-        objs
+        Ok(objs)
     }
 }
@@ -1,6 +1,7 @@
 use crate::camera::Camera;
 use crate::math::Vec3;
-use crate::world::{ray_color, World};
+use crate::tonemap::ToneMap;
+use crate::world::{ray_color, PathConfig, World};
 use pixels::{Pixels, SurfaceTexture};
 use std::sync::{Arc, Mutex};
 use winit::event::{Event, VirtualKeyCode};
@@ -20,6 +21,8 @@ impl Preview {
         world: Arc<dyn World>,
         camera: Camera,
         background: Box<dyn Fn(Vec3) -> Vec3 + Sync + Send>,
+        config: PathConfig,
+        tone_map: ToneMap,
     ) {
         let event_loop = EventLoop::new();
         let window = Window::new(&event_loop).unwrap();
@@ -30,7 +33,11 @@ impl Preview {
 
         let calculate_pixels = pixels.clone();
 
+        let pixel = (1. / (nx as f32 - 1.), 1. / (ny as f32 - 1.));
+
         std::thread::spawn(move || {
+            let mut sum = vec![Vec3::from(0.); nx * ny];
+
             for s in 0.. {
                 let layer = (0..ny)
                     .into_par_iter()
@@ -42,9 +49,13 @@ impl Preview {
                                 let u = (x as f32 + rng.gen::<f32>()) / (nx as f32 - 1.);
                                 let v = (y as f32 + rng.gen::<f32>()) / (ny as f32 - 1.);
 
-                                let ray = camera.get_ray(u, v, &mut rng);
-
-                                ray_color(ray, &world, &background, &mut rng)
+                                match camera.get_ray_differential(u, v, pixel, &mut rng) {
+                                    Some(ray) => {
+                                        camera.exposure_scale()
+                                            * ray_color(ray, &world, &background, &config, &mut rng)
+                                    }
+                                    None => Vec3::from(0.),
+                                }
                             })
                             .collect::<Vec<Vec3>>()
                     })
@@ -53,16 +64,15 @@ impl Preview {
                 {
                     let mut pixels = calculate_pixels.lock().unwrap();
                     let frame = pixels.get_frame();
-                    for (c, pixel) in layer.iter().zip(frame.chunks_exact_mut(4)) {
-                        let p = Vec3::new(pixel[0] as f32, pixel[1] as f32, pixel[2] as f32) / 255.;
-                        let p = p * p;
-                        pixel[3] = 0xff;
-
-                        let p = 255. * ((p * (s as f32) + *c) / (s as f32 + 1.)).sqrt();
+                    for ((c, acc), pixel) in layer
+                        .iter()
+                        .zip(sum.iter_mut())
+                        .zip(frame.chunks_exact_mut(4))
+                    {
+                        *acc = *acc + *c;
 
-                        pixel[0] = p.x() as u8;
-                        pixel[1] = p.y() as u8;
-                        pixel[2] = p.z() as u8;
+                        let [r, g, b] = tone_map.encode(*acc / (s as f32 + 1.));
+                        pixel.copy_from_slice(&[r, g, b, 0xff]);
                     }
                 }
 
@@ -1,10 +1,13 @@
-use crate::math::{Mat4, Vec3, Vec4};
+use crate::math::{Fold, Mat4, Quat, Vec3, Vec4, ZipMap};
 use crate::ray::Ray;
 
 use crate::aabb::AABB;
+use crate::light::Light;
 use crate::material::Material;
+use crate::texture::TexCoord;
 use crate::EPSILON;
-use std::ops::Range;
+use std::cmp::Ordering;
+use std::ops::{Add, Range};
 use std::sync::Arc;
 
 #[derive(Clone)]
@@ -15,6 +18,22 @@ pub struct HitRecord<'m> {
     material: &'m Material,
     uv: Vec3,
     front_face: bool,
+    object_id: u32,
+    material_id: u32,
+    /// Derivatives of the point with respect to `uv`, zero if unknown.
+    dpdu: Vec3,
+    dpdv: Vec3,
+    /// Derivatives of the unit normal with respect to `uv`, zero for flat surfaces. They're
+    /// taken before the normal is flipped to face the ray.
+    dndu: Vec3,
+    dndv: Vec3,
+    /// Change of the point and `uv` from one pixel to the next, see `compute_differentials`.
+    dpdx: Vec3,
+    dpdy: Vec3,
+    duvdx: Vec3,
+    duvdy: Vec3,
+    dndx: Vec3,
+    dndy: Vec3,
 }
 
 impl<'m> HitRecord<'m> {
@@ -29,9 +48,79 @@ impl<'m> HitRecord<'m> {
             front_face,
             material,
             uv,
+            object_id: 0,
+            material_id: 0,
+            dpdu: Vec3::from(0.),
+            dpdv: Vec3::from(0.),
+            dndu: Vec3::from(0.),
+            dndv: Vec3::from(0.),
+            dpdx: Vec3::from(0.),
+            dpdy: Vec3::from(0.),
+            duvdx: Vec3::from(0.),
+            duvdy: Vec3::from(0.),
+            dndx: Vec3::from(0.),
+            dndy: Vec3::from(0.),
         }
     }
 
+    /// Estimates the footprint of a pixel around the hit from the differentials of `ray`,
+    /// the ray that was traced, by intersecting its offset rays with the tangent plane. The
+    /// derivatives stay zero without differentials.
+    pub fn compute_differentials(&mut self, ray: &Ray) {
+        let d = match ray.differential {
+            Some(d) => d,
+            None => return,
+        };
+
+        // Moving objects report points at rest, so take the point on the traced ray
+        let p = ray.at(self.t);
+        let n = self.normal;
+        let offset = |origin: Vec3, dir: Vec3| {
+            let t = Vec3::dot(n, p - origin) / Vec3::dot(n, dir);
+            origin + t * dir - p
+        };
+
+        let (dpdx, dpdy) = (offset(d.rx_origin, d.rx_dir), offset(d.ry_origin, d.ry_dir));
+
+        if !(dpdx.fold(0., Add::add) + dpdy.fold(0., Add::add)).is_finite() {
+            return;
+        }
+
+        self.dpdx = dpdx;
+        self.dpdy = dpdy;
+
+        // Least squares solution of `dpdx = dudx * dpdu + dvdx * dpdv`, and likewise for y
+        let (dpdu, dpdv) = (self.dpdu, self.dpdv);
+        let (a00, a01, a11) = (
+            Vec3::dot(dpdu, dpdu),
+            Vec3::dot(dpdu, dpdv),
+            Vec3::dot(dpdv, dpdv),
+        );
+        let inv_det = 1. / (a00 * a11 - a01 * a01);
+
+        if !inv_det.is_finite() {
+            return;
+        }
+
+        let duv = |dp: Vec3| {
+            let (b0, b1) = (Vec3::dot(dpdu, dp), Vec3::dot(dpdv, dp));
+            Vec3::new(
+                (a11 * b0 - a01 * b1) * inv_det,
+                (a00 * b1 - a01 * b0) * inv_det,
+                0.,
+            )
+        };
+
+        self.duvdx = duv(dpdx);
+        self.duvdy = duv(dpdy);
+
+        let sign = if self.front_face { 1. } else { -1. };
+        let (dndu, dndv) = (self.dndu, self.dndv);
+        let dn = |duv: Vec3| sign * (duv.x() * dndu + duv.y() * dndv);
+        self.dndx = dn(self.duvdx);
+        self.dndy = dn(self.duvdy);
+    }
+
     #[inline]
     pub fn t(&self) -> f32 {
         self.t
@@ -61,11 +150,62 @@ impl<'m> HitRecord<'m> {
     pub fn uv(&self) -> Vec3 {
         self.uv
     }
+
+    #[inline]
+    pub fn object_id(&self) -> u32 {
+        self.object_id
+    }
+
+    #[inline]
+    pub fn material_id(&self) -> u32 {
+        self.material_id
+    }
+
+    #[inline]
+    pub fn dpdx(&self) -> Vec3 {
+        self.dpdx
+    }
+
+    #[inline]
+    pub fn dpdy(&self) -> Vec3 {
+        self.dpdy
+    }
+
+    /// Change of the normal from one pixel to the next, see `compute_differentials`.
+    #[inline]
+    pub fn dndx(&self) -> Vec3 {
+        self.dndx
+    }
+
+    #[inline]
+    pub fn dndy(&self) -> Vec3 {
+        self.dndy
+    }
+
+    /// Texture lookup at the hit, filtered over the pixel footprint if it's known.
+    #[inline]
+    pub fn tex_coord(&self) -> TexCoord {
+        TexCoord {
+            uv: self.uv,
+            p: self.point,
+            duvdx: self.duvdx,
+            duvdy: self.duvdy,
+            dpdx: self.dpdx,
+            dpdy: self.dpdy,
+        }
+    }
 }
 
 pub trait Hittable: Send + Sync {
     fn hit(&self, ray: &Ray, t: Range<f32>, rng: &mut dyn FnMut() -> f32) -> Option<HitRecord>;
     fn bounding_box(&self, exposure: Range<f32>) -> AABB;
+
+    /// Emissive parts of the object that can be sampled directly. Emitters left out are
+    /// only found by BSDF sampling: spheres instanced with a non-uniform scale and anything
+    /// inside a `ConstantMedium`, whose boundary doesn't emit.
+    fn lights(&self) -> Vec<Light> {
+        vec![]
+    }
 }
 
 impl Hittable for Box<dyn Hittable> {
@@ -77,6 +217,9 @@ impl Hittable for Box<dyn Hittable> {
     fn bounding_box(&self, exposure: Range<f32>) -> AABB {
         (**self).bounding_box(exposure)
     }
+    fn lights(&self) -> Vec<Light> {
+        (**self).lights()
+    }
 }
 
 impl Hittable for Arc<dyn Hittable> {
@@ -88,6 +231,9 @@ impl Hittable for Arc<dyn Hittable> {
     fn bounding_box(&self, exposure: Range<f32>) -> AABB {
         (**self).bounding_box(exposure)
     }
+    fn lights(&self) -> Vec<Light> {
+        (**self).lights()
+    }
 }
 
 pub struct Sphere {
@@ -116,17 +262,16 @@ impl Hittable for Sphere {
                 if *tc <= t.end && *tc >= t.start {
                     let n = (ray.at(*tc) - self.center) / self.radius;
                     let n = n.unit();
-                    let u = ((-n.z()).atan2(n.x()) + std::f32::consts::PI)
-                        / (2. * std::f32::consts::PI);
-                    let v = (-n.y()).acos() / std::f32::consts::PI;
-
-                    return Some(HitRecord::new(
-                        ray,
-                        *tc,
-                        n,
-                        Vec3::new(u, v, 0.),
-                        &self.material,
-                    ));
+
+                    let (dpdu, dpdv) = sphere_tangents(n, self.radius);
+
+                    return Some(HitRecord {
+                        dpdu,
+                        dpdv,
+                        dndu: dpdu / self.radius,
+                        dndv: dpdv / self.radius,
+                        ..HitRecord::new(ray, *tc, n, sphere_uv(n), &self.material)
+                    });
                 }
             }
             None
@@ -140,6 +285,42 @@ impl Hittable for Sphere {
             max: self.center + Vec3::from(self.radius),
         }
     }
+
+    fn lights(&self) -> Vec<Light> {
+        if self.material.is_emissive() {
+            vec![Light::sphere(
+                self.center,
+                self.radius,
+                self.material.clone(),
+            )]
+        } else {
+            vec![]
+        }
+    }
+}
+
+/// Texture coordinates of the unit sphere normal `n`.
+pub(crate) fn sphere_uv(n: Vec3) -> Vec3 {
+    let u = ((-n.z()).atan2(n.x()) + std::f32::consts::PI) / (2. * std::f32::consts::PI);
+    let v = (-n.y()).acos() / std::f32::consts::PI;
+    Vec3::new(u, v, 0.)
+}
+
+/// Derivatives of the point on a sphere of `radius` with respect to `sphere_uv(n)`.
+fn sphere_tangents(n: Vec3, radius: f32) -> (Vec3, Vec3) {
+    // Polar angle, clamped away from the poles where `u` is degenerate
+    let (sin, cos) = ((1. - n.y() * n.y()).sqrt().max(1e-4), -n.y());
+
+    (
+        2. * std::f32::consts::PI * radius * Vec3::new(n.z(), 0., -n.x()),
+        std::f32::consts::PI * radius * Vec3::new(cos * n.x() / sin, sin, cos * n.z() / sin),
+    )
+}
+
+/// Derivative of `n.unit()` given the derivative `dn` of `n`, whichever way `n` faces.
+fn unit_derivative(n: Vec3, dn: Vec3) -> Vec3 {
+    let unit = n.unit();
+    (dn - Vec3::dot(unit, dn) * unit) / n.len()
 }
 
 pub struct LinearMove<H> {
@@ -175,6 +356,316 @@ impl<H: Hittable> Hittable for LinearMove<H> {
 
         AABB::merge(&bb0, &bb1)
     }
+
+    fn lights(&self) -> Vec<Light> {
+        let velocity = self.velocity;
+
+        self.object
+            .lights()
+            .iter()
+            .map(|l| l.moving(move |time| Transform::translate(velocity * time)))
+            .collect()
+    }
+}
+
+/// Decomposed transform at a point in time, see `MotionTransform`.
+#[derive(Debug, Copy, Clone)]
+pub struct Keyframe {
+    pub time: f32,
+    pub translation: Vec3,
+    pub rotation: Quat,
+    pub scale: Vec3,
+}
+
+impl Keyframe {
+    /// `transform` must not contain shear, which can't be interpolated.
+    pub fn new(time: f32, transform: &Transform) -> Self {
+        let (translation, rotation, scale) = transform.decompose();
+
+        Keyframe {
+            time,
+            translation,
+            rotation,
+            scale,
+        }
+    }
+
+    fn transform(&self) -> Transform {
+        Transform::translate(self.translation)
+            * Transform::rotation(self.rotation)
+            * Transform::scale(self.scale)
+    }
+}
+
+/// Moves `object` by a transform interpolated between keyframes at the time of each ray.
+/// Translation and scale are interpolated linearly and rotation by slerp; the transform is
+/// held constant before the first and after the last keyframe.
+pub struct MotionTransform<H> {
+    object: H,
+    /// Sorted by time, shared with the lights of `object`.
+    keyframes: Arc<[Keyframe]>,
+}
+
+impl<H: Hittable> MotionTransform<H> {
+    /// Panics if `keyframes` is empty or has a time that isn't finite.
+    pub fn new(object: H, mut keyframes: Vec<Keyframe>) -> Self {
+        assert!(!keyframes.is_empty(), "MotionTransform needs a keyframe.");
+        assert!(
+            keyframes.iter().all(|k| k.time.is_finite()),
+            "MotionTransform keyframe times must be finite."
+        );
+
+        keyframes.sort_by(|a, b| a.time.partial_cmp(&b.time).unwrap_or(Ordering::Equal));
+
+        MotionTransform {
+            object,
+            keyframes: keyframes.into(),
+        }
+    }
+
+    fn keyframe_at(&self, time: f32) -> Keyframe {
+        keyframe_at(&self.keyframes, time)
+    }
+
+    /// Transform at `time`.
+    pub fn at(&self, time: f32) -> Transform {
+        self.keyframe_at(time).transform()
+    }
+}
+
+/// Keyframe interpolated at `time` from the sorted `keyframes`.
+fn keyframe_at(keyframes: &[Keyframe], time: f32) -> Keyframe {
+    let next = keyframes.iter().position(|k| k.time > time);
+
+    match next {
+        Some(0) => keyframes[0],
+        None => keyframes[keyframes.len() - 1],
+        Some(i) => {
+            let (a, b) = (&keyframes[i - 1], &keyframes[i]);
+            let t = if b.time > a.time {
+                (time - a.time) / (b.time - a.time)
+            } else {
+                1.
+            };
+
+            Keyframe {
+                time,
+                translation: (1. - t) * a.translation + t * b.translation,
+                rotation: Quat::slerp(a.rotation, b.rotation, t),
+                scale: (1. - t) * a.scale + t * b.scale,
+            }
+        }
+    }
+}
+
+impl<H: Hittable> Hittable for MotionTransform<H> {
+    fn hit(&self, ray: &Ray, t: Range<f32>, rng: &mut dyn FnMut() -> f32) -> Option<HitRecord> {
+        let transform = self.at(ray.t);
+        let to_object = transform.inverse();
+
+        let local = Ray {
+            origin: to_object.point(ray.origin),
+            dir: to_object.vector(ray.dir),
+            t: ray.t,
+            differential: None,
+        };
+
+        self.object.hit(&local, t, rng).map(|h| {
+            let n = transform.normal(h.normal);
+
+            HitRecord {
+                point: transform.point(h.point),
+                normal: n.unit(),
+                dpdu: transform.vector(h.dpdu),
+                dpdv: transform.vector(h.dpdv),
+                dndu: unit_derivative(n, transform.normal(h.dndu)),
+                dndv: unit_derivative(n, transform.normal(h.dndv)),
+                ..h
+            }
+        })
+    }
+
+    /// Bounds the motion piecewise between the exposure ends and the keyframes inside it.
+    /// Where the rotation doesn't change every point moves on a straight line, so the boxes
+    /// at both ends of the piece suffice. Otherwise the object is bounded by a ball around
+    /// the translation that contains it at any rotation.
+    fn bounding_box(&self, exposure: Range<f32>) -> AABB {
+        let bb = self.object.bounding_box(exposure.clone());
+
+        let corners: Vec<Vec3> = (0..8)
+            .map(|i| {
+                Vec3::new(
+                    if i & 1 == 0 { bb.min.x() } else { bb.max.x() },
+                    if i & 2 == 0 { bb.min.y() } else { bb.max.y() },
+                    if i & 4 == 0 { bb.min.z() } else { bb.max.z() },
+                )
+            })
+            .collect();
+        let extent = corners.iter().map(|c| c.len()).fold(0., f32::max);
+
+        let times: Vec<f32> = std::iter::once(exposure.start)
+            .chain(
+                self.keyframes
+                    .iter()
+                    .map(|k| k.time)
+                    .filter(|t| exposure.start < *t && *t < exposure.end),
+            )
+            .chain(std::iter::once(exposure.end))
+            .collect();
+
+        times.windows(2).fold(AABB::empty(), |acc, w| {
+            let (a, b) = (self.keyframe_at(w[0]), self.keyframe_at(w[1]));
+
+            let piece = if Quat::dot(a.rotation, b.rotation).abs() > 1. - 1e-6 {
+                [a, b].iter().fold(AABB::empty(), |acc, k| {
+                    let t = k.transform();
+                    corners.iter().fold(acc, |acc, c| {
+                        let p = t.point(*c);
+                        AABB::merge(&acc, &AABB { min: p, max: p })
+                    })
+                })
+            } else {
+                let radius = extent
+                    * a.scale
+                        .map(f32::abs)
+                        .max(b.scale.map(f32::abs))
+                        .fold(0., f32::max);
+
+                AABB {
+                    min: a.translation.min(b.translation) - Vec3::from(radius),
+                    max: a.translation.max(b.translation) + Vec3::from(radius),
+                }
+            };
+
+            AABB::merge(&acc, &piece)
+        })
+    }
+
+    fn lights(&self) -> Vec<Light> {
+        self.object
+            .lights()
+            .iter()
+            .map(|l| {
+                let keyframes = self.keyframes.clone();
+                l.moving(move |time| keyframe_at(&keyframes, time).transform())
+            })
+            .collect()
+    }
+}
+
+/// Marks every hit on `object` with `id` for the object ID render pass.
+pub struct Tagged<H> {
+    pub object: H,
+    pub id: u32,
+}
+
+impl<H: Hittable> Hittable for Tagged<H> {
+    #[inline]
+    fn hit(&self, ray: &Ray, t: Range<f32>, rng: &mut dyn FnMut() -> f32) -> Option<HitRecord> {
+        self.object.hit(ray, t, rng).map(|h| HitRecord {
+            object_id: self.id,
+            ..h
+        })
+    }
+
+    #[inline]
+    fn bounding_box(&self, exposure: Range<f32>) -> AABB {
+        self.object.bounding_box(exposure)
+    }
+
+    fn lights(&self) -> Vec<Light> {
+        self.object.lights()
+    }
+}
+
+/// Marks every hit on `object` with `id` for the material ID render pass.
+pub struct MaterialTagged<H> {
+    pub object: H,
+    pub id: u32,
+}
+
+impl<H: Hittable> Hittable for MaterialTagged<H> {
+    #[inline]
+    fn hit(&self, ray: &Ray, t: Range<f32>, rng: &mut dyn FnMut() -> f32) -> Option<HitRecord> {
+        self.object.hit(ray, t, rng).map(|h| HitRecord {
+            material_id: self.id,
+            ..h
+        })
+    }
+
+    #[inline]
+    fn bounding_box(&self, exposure: Range<f32>) -> AABB {
+        self.object.bounding_box(exposure)
+    }
+
+    fn lights(&self) -> Vec<Light> {
+        self.object.lights()
+    }
+}
+
+/// A shared object placed in the scene by a transform, e.g. one of many copies of a mesh.
+///
+/// Rays are brought into object space with the inverse matrix. As the direction isn't
+/// renormalized, hit distances are the same in both spaces.
+pub struct Instance {
+    object: Arc<dyn Hittable>,
+    transform: Transform,
+}
+
+impl Instance {
+    pub fn new(object: Arc<dyn Hittable>, transform: Transform) -> Self {
+        Instance { object, transform }
+    }
+}
+
+impl Hittable for Instance {
+    fn hit(&self, ray: &Ray, t: Range<f32>, rng: &mut dyn FnMut() -> f32) -> Option<HitRecord> {
+        let to_object = self.transform.inverse();
+
+        let local = Ray {
+            origin: to_object.point(ray.origin),
+            dir: to_object.vector(ray.dir),
+            t: ray.t,
+            differential: None,
+        };
+
+        // The transformed normal keeps facing the ray
+        self.object.hit(&local, t, rng).map(|h| {
+            let n = self.transform.normal(h.normal);
+
+            HitRecord {
+                point: self.transform.point(h.point),
+                normal: n.unit(),
+                dpdu: self.transform.vector(h.dpdu),
+                dpdv: self.transform.vector(h.dpdv),
+                dndu: unit_derivative(n, self.transform.normal(h.dndu)),
+                dndv: unit_derivative(n, self.transform.normal(h.dndv)),
+                ..h
+            }
+        })
+    }
+
+    fn bounding_box(&self, exposure: Range<f32>) -> AABB {
+        let bb = self.object.bounding_box(exposure);
+
+        (0..8).fold(AABB::empty(), |acc, i| {
+            let c = self.transform.point(Vec3::new(
+                if i & 1 == 0 { bb.min.x() } else { bb.max.x() },
+                if i & 2 == 0 { bb.min.y() } else { bb.max.y() },
+                if i & 4 == 0 { bb.min.z() } else { bb.max.z() },
+            ));
+
+            AABB::merge(&acc, &AABB { min: c, max: c })
+        })
+    }
+
+    fn lights(&self) -> Vec<Light> {
+        self.object
+            .lights()
+            .iter()
+            .filter_map(|l| l.transform(&self.transform))
+            .collect()
+    }
 }
 
 pub struct ConstantMedium {
@@ -265,14 +756,15 @@ impl Hittable for Triangle {
 
         if t_range.start <= t && t <= t_range.end {
             let (n1, n2, n3) = self.normals;
+            let n = n1 * (1. - u - v) + u * n2 + v * n3;
 
-            Some(HitRecord::new(
-                ray,
-                t,
-                n1 * (1. - u - v) + u * n2 + v * n3,
-                Vec3::new(u, v, 0.),
-                &self.material,
-            ))
+            Some(HitRecord {
+                dpdu: edge1,
+                dpdv: edge2,
+                dndu: unit_derivative(n, n2 - n1),
+                dndv: unit_derivative(n, n3 - n1),
+                ..HitRecord::new(ray, t, n, Vec3::new(u, v, 0.), &self.material)
+            })
         } else {
             None
         }
@@ -286,29 +778,138 @@ impl Hittable for Triangle {
 
         AABB { min, max }
     }
+
+    fn lights(&self) -> Vec<Light> {
+        if self.material.is_emissive() {
+            vec![Light::triangle(self.vertices, self.material.clone())]
+        } else {
+            vec![]
+        }
+    }
 }
 
-pub struct Transform(pub Mat4);
+/// Affine transform that keeps its inverse alongside, so that composing transforms never
+/// needs a matrix inversion.
+#[derive(Debug, Copy, Clone)]
+pub struct Transform {
+    matrix: Mat4,
+    inverse: Mat4,
+}
 
 impl Transform {
+    /// `None` if `matrix` is singular.
+    pub fn new(matrix: Mat4) -> Option<Self> {
+        matrix.inv().map(|inverse| Transform { matrix, inverse })
+    }
+
+    #[inline]
+    pub fn matrix(&self) -> &Mat4 {
+        &self.matrix
+    }
+
+    #[inline]
+    pub fn inverse_matrix(&self) -> &Mat4 {
+        &self.inverse
+    }
+
+    #[inline]
+    pub fn inverse(&self) -> Transform {
+        Transform {
+            matrix: self.inverse,
+            inverse: self.matrix,
+        }
+    }
+
     pub fn apply(&self, p: Vec4) -> Vec4 {
-        self.0 * p
+        self.matrix * p
+    }
+
+    #[inline]
+    pub fn point(&self, p: Vec3) -> Vec3 {
+        let p = self.apply(Vec4::new(p.x(), p.y(), p.z(), 1.));
+        p.xyz() / p.w()
+    }
+
+    #[inline]
+    pub fn vector(&self, v: Vec3) -> Vec3 {
+        self.apply(Vec4::new(v.x(), v.y(), v.z(), 0.)).xyz()
+    }
+
+    /// Transforms a surface normal by the inverse transpose, which keeps it perpendicular to
+    /// the surface under non-uniform scale. The result isn't normalized.
+    #[inline]
+    pub fn normal(&self, n: Vec3) -> Vec3 {
+        (self.inverse.transpose() * Vec4::new(n.x(), n.y(), n.z(), 0.)).xyz()
+    }
+
+    /// Splits an affine transform without shear into translation, rotation and scale, such
+    /// that it equals `translate(t) * rotation(r) * scale(s)`.
+    pub fn decompose(&self) -> (Vec3, Quat, Vec3) {
+        let m = &self.matrix.0;
+        let column = |j: usize| Vec3::new(m[j], m[4 + j], m[8 + j]);
+
+        let translation = column(3);
+        let mut scale = Vec3::new(column(0).len(), column(1).len(), column(2).len());
+
+        // Mirroring is folded into the x scale so that the rest is a proper rotation
+        if self.matrix.det() < 0. {
+            scale = scale * Vec3::new(-1., 1., 1.);
+        }
+
+        let rotation = Mat4([
+            m[0] / scale.x(),
+            m[1] / scale.y(),
+            m[2] / scale.z(),
+            0.,
+            m[4] / scale.x(),
+            m[5] / scale.y(),
+            m[6] / scale.z(),
+            0.,
+            m[8] / scale.x(),
+            m[9] / scale.y(),
+            m[10] / scale.z(),
+            0.,
+            0.,
+            0.,
+            0.,
+            1.,
+        ]);
+
+        (translation, Quat::from_mat4(&rotation), scale)
+    }
+
+    /// Whether `decompose` loses part of the transform, e.g. a non-uniform scale applied
+    /// after a rotation.
+    pub fn shears(&self) -> bool {
+        let (t, r, s) = self.decompose();
+        let parts = Transform::translate(t) * Transform::rotation(r) * Transform::scale(s);
+
+        let (a, b) = (&self.matrix.0, &parts.matrix.0);
+        let size = a.iter().fold(1f32, |m, v| m.max(v.abs()));
+
+        a.iter().zip(b).any(|(x, y)| (x - y).abs() > 1e-4 * size)
     }
 
     pub fn identity() -> Self {
-        Transform(Mat4::eye())
+        Transform {
+            matrix: Mat4::eye(),
+            inverse: Mat4::eye(),
+        }
     }
 
+    /// Composes the transforms so that the last one is applied first.
     pub fn stack<'m>(ts: impl Iterator<Item = &'m Transform>) -> Transform {
-        Transform(ts.fold(Mat4::eye(), |acc, t| acc * t.0))
+        ts.fold(Transform::identity(), |acc, t| acc * *t)
     }
 
+    /// Rotation by the Euler angles `a`, `b` and `c` around z, y and x, applied in reverse
+    /// order.
     pub fn rotate(a: f32, b: f32, c: f32) -> Transform {
         let (sa, ca) = a.sin_cos();
         let (sb, cb) = b.sin_cos();
         let (sc, cc) = c.sin_cos();
 
-        Transform(Mat4([
+        let matrix = Mat4([
             ca * cb,
             ca * sb * sc - sa * cc,
             ca * sb * cc + sa * sc,
@@ -325,48 +926,323 @@ impl Transform {
             0.,
             0.,
             1.,
-        ]))
+        ]);
+
+        Transform {
+            matrix,
+            inverse: matrix.transpose(),
+        }
+    }
+
+    /// Rotation by `angle` radians counterclockwise around `axis`.
+    pub fn rotate_axis(axis: Vec3, angle: f32) -> Transform {
+        Transform::rotation(Quat::from_axis_angle(axis, angle))
+    }
+
+    pub fn rotation(q: Quat) -> Transform {
+        let matrix = q.unit().to_mat4();
+
+        Transform {
+            matrix,
+            inverse: matrix.transpose(),
+        }
     }
 
     pub fn translate(t: Vec3) -> Transform {
-        Transform(Mat4([
-            1.,
-            0.,
-            0.,
-            t.x(),
-            0.,
-            1.,
-            0.,
-            t.y(),
-            0.,
-            0.,
-            1.,
-            t.z(),
-            0.,
-            0.,
-            0.,
-            1.,
-        ]))
+        let matrix = |t: Vec3| {
+            Mat4([
+                1.,
+                0.,
+                0.,
+                t.x(),
+                0.,
+                1.,
+                0.,
+                t.y(),
+                0.,
+                0.,
+                1.,
+                t.z(),
+                0.,
+                0.,
+                0.,
+                1.,
+            ])
+        };
+
+        Transform {
+            matrix: matrix(t),
+            inverse: matrix(-t),
+        }
     }
 
     pub fn scale(s: Vec3) -> Transform {
-        Transform(Mat4([
-            s.x(),
-            0.,
-            0.,
-            0.,
-            0.,
-            s.y(),
-            0.,
-            0.,
+        let matrix = |s: Vec3| {
+            Mat4([
+                s.x(),
+                0.,
+                0.,
+                0.,
+                0.,
+                s.y(),
+                0.,
+                0.,
+                0.,
+                0.,
+                s.z(),
+                0.,
+                0.,
+                0.,
+                0.,
+                1.,
+            ])
+        };
+
+        Transform {
+            matrix: matrix(s),
+            inverse: matrix(s.map(|v| 1. / v)),
+        }
+    }
+
+    /// Frame at `from` with its z axis towards `to` and its y axis in the plane of `up`.
+    /// Maps the local frame to world space, so it places an object looking at `to`.
+    pub fn look_at(from: Vec3, to: Vec3, up: Vec3) -> Transform {
+        let w = (to - from).unit();
+        let u = Vec3::cross(up, w).unit();
+        let v = Vec3::cross(w, u);
+
+        let matrix = Mat4([
+            u.x(),
+            v.x(),
+            w.x(),
+            from.x(),
+            u.y(),
+            v.y(),
+            w.y(),
+            from.y(),
+            u.z(),
+            v.z(),
+            w.z(),
+            from.z(),
             0.,
             0.,
-            s.z(),
             0.,
+            1.,
+        ]);
+
+        // Rigid, so the inverse is the transposed rotation followed by the negated translation
+        let inverse = Mat4([
+            u.x(),
+            u.y(),
+            u.z(),
+            -Vec3::dot(u, from),
+            v.x(),
+            v.y(),
+            v.z(),
+            -Vec3::dot(v, from),
+            w.x(),
+            w.y(),
+            w.z(),
+            -Vec3::dot(w, from),
             0.,
             0.,
             0.,
             1.,
-        ]))
+        ]);
+
+        Transform { matrix, inverse }
+    }
+}
+
+impl std::ops::Mul for Transform {
+    type Output = Transform;
+
+    /// Composes the transforms, applying `rhs` first.
+    fn mul(self, rhs: Self) -> Self::Output {
+        Transform {
+            matrix: self.matrix * rhs.matrix,
+            inverse: rhs.inverse * self.inverse,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(a: Vec3, b: Vec3) {
+        assert!((a - b).len() < 1e-4, "{:?} != {:?}", a, b);
+    }
+
+    #[test]
+    fn decompose_recovers_parts() {
+        let rotation = Quat::from_axis_angle(Vec3::new(1., 2., 0.), 0.7);
+        let transform = Transform::translate(Vec3::new(1., -2., 3.))
+            * Transform::rotation(rotation)
+            * Transform::scale(Vec3::new(2., 3., 4.));
+
+        let (t, r, s) = transform.decompose();
+
+        assert_close(t, Vec3::new(1., -2., 3.));
+        assert!(Quat::dot(r, rotation).abs() > 1. - 1e-5);
+        assert_close(s, Vec3::new(2., 3., 4.));
+    }
+
+    #[test]
+    fn decompose_folds_mirroring_into_x() {
+        let (_, r, s) = Transform::scale(Vec3::new(1., -1., 1.)).decompose();
+        let p = Vec3::new(1., 2., 3.);
+
+        assert_eq!(s.x(), -1.);
+        assert_close(r.rotate(s * p), Vec3::new(1., -2., 3.));
+    }
+
+    #[test]
+    fn shears_detects_scale_after_rotation() {
+        let rotation = Transform::rotate(0.5, 0.2, 0.);
+
+        assert!((Transform::scale(Vec3::new(2., 1., 1.)) * rotation).shears());
+        assert!(!(rotation * Transform::scale(Vec3::new(2., 1., 1.))).shears());
+        assert!(!(Transform::scale(Vec3::from(3.)) * rotation).shears());
+        assert!(!Transform::scale(Vec3::new(-1., 2., 1.)).shears());
+    }
+
+    fn motion(keyframes: &[(f32, Vec3)]) -> MotionTransform<Sphere> {
+        let sphere = Sphere {
+            radius: 1.,
+            center: Vec3::from(0.),
+            material: Material::Empty,
+        };
+
+        MotionTransform::new(
+            sphere,
+            keyframes
+                .iter()
+                .map(|(t, p)| Keyframe::new(*t, &Transform::translate(*p)))
+                .collect(),
+        )
+    }
+
+    #[test]
+    #[should_panic]
+    fn motion_without_keyframes_panics() {
+        motion(&[]);
+    }
+
+    #[test]
+    fn motion_interpolates_and_holds() {
+        let m = motion(&[(1., Vec3::new(2., 0., 0.)), (0., Vec3::from(0.))]);
+        let origin = Vec3::from(0.);
+
+        assert_close(m.at(-1.).point(origin), Vec3::from(0.));
+        assert_close(m.at(0.25).point(origin), Vec3::new(0.5, 0., 0.));
+        assert_close(m.at(2.).point(origin), Vec3::new(2., 0., 0.));
+    }
+
+    #[test]
+    fn motion_keyframes_at_same_time_step() {
+        let m = motion(&[
+            (0., Vec3::from(0.)),
+            (1., Vec3::new(1., 0., 0.)),
+            (1., Vec3::new(5., 0., 0.)),
+        ]);
+        let p = m.at(1.).point(Vec3::from(0.));
+
+        assert!(p.x().is_finite());
+        assert_close(p, Vec3::new(5., 0., 0.));
+    }
+
+    /// Ray along `dir` whose offset rays start `offset` to the side along x and y.
+    fn differential_ray(origin: Vec3, dir: Vec3, offset: f32) -> Ray {
+        Ray {
+            origin,
+            dir,
+            t: 0.,
+            differential: Some(crate::ray::RayDifferential {
+                rx_origin: origin + Vec3::new(offset, 0., 0.),
+                rx_dir: dir,
+                ry_origin: origin + Vec3::new(0., offset, 0.),
+                ry_dir: dir,
+            }),
+        }
+    }
+
+    fn hit_with_differentials<'m>(object: &'m dyn Hittable, ray: &Ray) -> HitRecord<'m> {
+        let mut hit = object.hit(ray, 0.001..f32::INFINITY, &mut || 0.5).unwrap();
+        hit.compute_differentials(ray);
+        hit
+    }
+
+    fn sphere(radius: f32) -> Sphere {
+        Sphere {
+            radius,
+            center: Vec3::from(0.),
+            material: Material::Empty,
+        }
+    }
+
+    #[test]
+    fn sphere_normal_tilts_across_pixel() {
+        let sphere = sphere(2.);
+        let ray = differential_ray(Vec3::new(0., 0., 5.), Vec3::new(0., 0., -1.), 0.01);
+        let hit = hit_with_differentials(&sphere, &ray);
+
+        assert_close(hit.dndx(), Vec3::new(0.005, 0., 0.));
+        assert_close(hit.dndy(), Vec3::new(0., 0.005, 0.));
+    }
+
+    #[test]
+    fn sphere_normal_derivatives_follow_flipped_normal() {
+        let sphere = sphere(2.);
+        let ray = differential_ray(Vec3::from(0.), Vec3::new(0., 0., 1.), 0.01);
+        let hit = hit_with_differentials(&sphere, &ray);
+
+        assert_close(hit.normal(), Vec3::new(0., 0., -1.));
+        assert_close(hit.dndx(), Vec3::new(-0.005, 0., 0.));
+    }
+
+    #[test]
+    fn instance_scales_normal_derivatives() {
+        let instance = Instance::new(Arc::new(sphere(1.)), Transform::scale(Vec3::from(2.)));
+        let ray = differential_ray(Vec3::new(0., 0., 5.), Vec3::new(0., 0., -1.), 0.01);
+        let hit = hit_with_differentials(&instance, &ray);
+
+        assert_close(hit.dndx(), Vec3::new(0.005, 0., 0.));
+    }
+
+    #[test]
+    fn triangle_normal_derivatives_match_finite_differences() {
+        let triangle = Triangle {
+            vertices: (
+                Vec3::new(-1., -1., 0.),
+                Vec3::new(1., -1., 0.),
+                Vec3::new(-1., 1., 0.),
+            ),
+            normals: (
+                Vec3::new(0., 0., 1.),
+                Vec3::new(0.5, 0., 1.),
+                Vec3::new(0., -0.5, 1.),
+            ),
+            material: Material::Empty,
+        };
+        let normal_at = |x: f32, y: f32| {
+            let ray = Ray {
+                origin: Vec3::new(x, y, 1.),
+                dir: Vec3::new(0., 0., -1.),
+                t: 0.,
+                differential: None,
+            };
+            triangle
+                .hit(&ray, 0.001..f32::INFINITY, &mut || 0.5)
+                .unwrap()
+                .normal()
+        };
+
+        let (x, y, offset) = (-0.4, -0.2, 1e-2);
+        let ray = differential_ray(Vec3::new(x, y, 1.), Vec3::new(0., 0., -1.), offset);
+        let hit = hit_with_differentials(&triangle, &ray);
+
+        assert_close(hit.dndx(), normal_at(x + offset, y) - normal_at(x, y));
+        assert_close(hit.dndy(), normal_at(x, y + offset) - normal_at(x, y));
     }
 }
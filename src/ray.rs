@@ -5,6 +5,8 @@ pub struct Ray {
     pub origin: Vec3,
     pub dir: Vec3,
     pub t: f32,
+    /// Rays through the neighbouring pixels, if known.
+    pub differential: Option<RayDifferential>,
 }
 
 impl Ray {
@@ -12,3 +14,13 @@ impl Ray {
         self.origin + t * self.dir
     }
 }
+
+/// Offset rays one pixel to the right and one pixel up from a camera ray, which tell how
+/// large a pixel's footprint is where the ray hits a surface.
+#[derive(Copy, Clone, Debug)]
+pub struct RayDifferential {
+    pub rx_origin: Vec3,
+    pub rx_dir: Vec3,
+    pub ry_origin: Vec3,
+    pub ry_dir: Vec3,
+}
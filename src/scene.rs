@@ -0,0 +1,1030 @@
+use crate::assets::Assets;
+use crate::bvh::Bvh;
+use crate::camera::{Aperture, ApertureMask, Camera, CameraKey, PhysicalCamera, Projection};
+use crate::color::{ColorSpace, WorkingSpace};
+use crate::material::Material;
+use crate::math::{Axis3::*, Mat4, Quat, Vec3};
+use crate::mesh::Mesh;
+use crate::primitive::{
+    ConstantMedium, Hittable, Instance, Keyframe, LinearMove, MaterialTagged, MotionTransform,
+    Sphere, Transform, Triangle,
+};
+use crate::texture::{self, Filter, Sampler, TexCoord, Texture, UvTransform, Wrap};
+use crate::world::Background;
+
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::f32::consts::PI;
+use std::fmt;
+use std::ops::Range;
+use std::path::Path;
+use std::sync::Arc;
+
+pub type Scene = (Camera, Vec<Box<dyn Hittable>>, Background);
+
+#[derive(Debug)]
+pub enum SceneError {
+    Io(std::io::Error),
+    Parse(ron::Error),
+    UnknownTexture(String),
+    UnknownMaterial(String),
+    UnknownPrototype(String),
+    EmptyObject,
+    Image(String, Box<dyn std::error::Error>),
+    Mesh(String, tobj::LoadError),
+    EmptyApertureMask(String),
+    EmptyKeys,
+    SingularTransform,
+    InvalidKeyTime(f32),
+    ShearedKeyframe(f32),
+}
+
+impl fmt::Display for SceneError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SceneError::Io(e) => write!(f, "couldn't read scene: {}", e),
+            SceneError::Parse(e) => write!(f, "failed to deserialize scene: {}", e),
+            SceneError::UnknownTexture(name) => write!(f, "unknown texture \"{}\"", name),
+            SceneError::UnknownMaterial(name) => write!(f, "unknown material \"{}\"", name),
+            SceneError::UnknownPrototype(name) => write!(f, "unknown prototype \"{}\"", name),
+            SceneError::EmptyObject => write!(f, "no geometry, e.g. an empty mesh or scene"),
+            SceneError::Image(path, e) => write!(f, "couldn't load image {}: {}", path, e),
+            SceneError::Mesh(path, e) => write!(f, "couldn't load mesh {}: {}", path, e),
+            SceneError::EmptyApertureMask(path) => {
+                write!(f, "aperture mask {} is black everywhere", path)
+            }
+            SceneError::EmptyKeys => write!(f, "animation without keys"),
+            SceneError::SingularTransform => {
+                write!(f, "transform isn't invertible, e.g. scales by zero")
+            }
+            SceneError::InvalidKeyTime(t) => write!(f, "invalid key time {}", t),
+            SceneError::ShearedKeyframe(t) => {
+                write!(f, "keyframe at {} shears, e.g. scales after rotating", t)
+            }
+        }
+    }
+}
+
+impl std::error::Error for SceneError {}
+
+impl From<std::io::Error> for SceneError {
+    fn from(e: std::io::Error) -> Self {
+        SceneError::Io(e)
+    }
+}
+
+impl From<ron::Error> for SceneError {
+    fn from(e: ron::Error) -> Self {
+        SceneError::Parse(e)
+    }
+}
+
+/// Top level of a `.ron` scene file.
+///
+/// Textures and materials are named so that objects can share them. Named textures are
+/// built in alphabetical order and may only refer to textures that sort before them.
+/// Prototypes are objects that are built once and placed any number of times by instances.
+///
+/// Camera and material parameters may be animated, see `Animated`. The camera moves within
+/// the exposure like `Motion` objects, while materials are evaluated at its start.
+#[derive(Deserialize)]
+pub struct SceneDesc {
+    /// Seed for procedural textures.
+    #[serde(default)]
+    pub seed: u64,
+    pub camera: CameraDesc,
+    pub background: BackgroundDesc,
+    #[serde(default)]
+    pub textures: BTreeMap<String, TextureDesc>,
+    #[serde(default)]
+    pub materials: BTreeMap<String, MaterialDesc>,
+    #[serde(default)]
+    pub prototypes: BTreeMap<String, ObjectDesc>,
+    pub objects: Vec<ObjectDesc>,
+}
+
+/// Either a constant or a list of `(time, value)` keys, interpolated linearly between keys
+/// and held before the first and after the last. Keyed values are written as floats, e.g.
+/// `[(0., 40.), (2., 60.)]`.
+#[derive(Deserialize, Clone)]
+#[serde(untagged)]
+pub enum Animated<T> {
+    Constant(T),
+    Keyed(Vec<(f32, T)>),
+}
+
+impl<T> Animated<T> {
+    /// Times of the keys, none for constants.
+    pub fn times(&self) -> impl Iterator<Item = f32> + '_ {
+        let keys = match self {
+            Animated::Constant(_) => &[][..],
+            Animated::Keyed(keys) => &keys[..],
+        };
+
+        keys.iter().map(|(t, _)| *t)
+    }
+
+    /// Sorts the keys by time, failing if there are none or a time isn't finite.
+    fn sort_keys(&mut self) -> Result<(), SceneError> {
+        if let Animated::Keyed(keys) = self {
+            if keys.is_empty() {
+                return Err(SceneError::EmptyKeys);
+            }
+
+            if let Some((t, _)) = keys.iter().find(|(t, _)| !t.is_finite()) {
+                return Err(SceneError::InvalidKeyTime(*t));
+            }
+
+            keys.sort_by(|(a, _), (b, _)| a.partial_cmp(b).unwrap());
+        }
+
+        Ok(())
+    }
+}
+
+impl<T: Copy + Lerp> Animated<T> {
+    /// Value at `time`. Keys must be sorted and non-empty, as they are in loaded scenes.
+    pub fn at(&self, time: f32) -> T {
+        match self {
+            Animated::Constant(v) => *v,
+            Animated::Keyed(keys) => match keys.iter().position(|(t, _)| *t > time) {
+                Some(0) => keys[0].1,
+                None => keys[keys.len() - 1].1,
+                Some(i) => {
+                    let ((t0, a), (t1, b)) = (keys[i - 1], keys[i]);
+
+                    // Keys at the same time make a step
+                    if t1 > t0 {
+                        T::lerp(a, b, (time - t0) / (t1 - t0))
+                    } else {
+                        b
+                    }
+                }
+            },
+        }
+    }
+}
+
+impl<T: Default> Default for Animated<T> {
+    fn default() -> Self {
+        Animated::Constant(T::default())
+    }
+}
+
+pub trait Lerp {
+    fn lerp(a: Self, b: Self, t: f32) -> Self;
+}
+
+impl Lerp for f32 {
+    fn lerp(a: Self, b: Self, t: f32) -> Self {
+        (1. - t) * a + t * b
+    }
+}
+
+impl Lerp for Vec3 {
+    fn lerp(a: Self, b: Self, t: f32) -> Self {
+        (1. - t) * a + t * b
+    }
+}
+
+#[derive(Deserialize)]
+pub struct CameraDesc {
+    pub lookfrom: Animated<Vec3>,
+    pub lookat: Animated<Vec3>,
+    #[serde(default = "default_vup")]
+    pub vup: Animated<Vec3>,
+    /// Ignored with `physical`.
+    #[serde(default = "default_vfov")]
+    pub vfov: Animated<f32>,
+    /// Defaults to the distance between `lookfrom` and `lookat`.
+    #[serde(default)]
+    pub focus_distance: Option<Animated<f32>>,
+    /// Ignored with `physical`.
+    #[serde(default)]
+    pub aperture: Animated<f32>,
+    /// Derives the field of view, aperture, exposure interval and exposure scale from
+    /// photographic settings instead.
+    #[serde(default)]
+    pub physical: Option<PhysicalCamera>,
+    #[serde(default)]
+    pub aperture_shape: ApertureDesc,
+    /// Optical vignetting strength, see `Camera::with_cat_eye`.
+    #[serde(default)]
+    pub cat_eye: f32,
+    /// Rolling shutter readout time, see `Camera::with_rolling_shutter`.
+    #[serde(default)]
+    pub readout: f32,
+    #[serde(default)]
+    pub projection: Projection,
+}
+
+impl CameraDesc {
+    /// Keys the camera at both ends of the exposure and at every key of its animated values
+    /// in between, which reproduces their piecewise linear interpolation exactly.
+    fn build(
+        &self,
+        base: &Path,
+        aspect_ratio: f32,
+        exposure: Range<f32>,
+    ) -> Result<Camera, SceneError> {
+        let exposure = match &self.physical {
+            Some(physical) => physical.exposure(exposure.start),
+            None => exposure,
+        };
+
+        let mut times = vec![exposure.start, exposure.end];
+        times.extend(
+            self.lookfrom
+                .times()
+                .chain(self.lookat.times())
+                .chain(self.vup.times())
+                .chain(self.vfov.times())
+                .chain(self.focus_distance.iter().flat_map(|f| f.times()))
+                .chain(self.aperture.times())
+                .filter(|t| exposure.contains(t)),
+        );
+        times.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        times.dedup();
+
+        let keys = times
+            .into_iter()
+            .map(|time| {
+                let lookfrom = self.lookfrom.at(time);
+                let lookat = self.lookat.at(time);
+
+                CameraKey {
+                    time,
+                    lookfrom,
+                    lookat,
+                    vup: self.vup.at(time),
+                    vfov: match &self.physical {
+                        Some(physical) => physical.vfov(aspect_ratio),
+                        None => self.vfov.at(time),
+                    },
+                    focus_distance: self
+                        .focus_distance
+                        .as_ref()
+                        .map_or_else(|| (lookat - lookfrom).len(), |f| f.at(time)),
+                    aperture: match &self.physical {
+                        Some(physical) => physical.aperture(),
+                        None => self.aperture.at(time),
+                    },
+                }
+            })
+            .collect();
+
+        let exposure_scale = self.physical.map_or(1., |p| p.exposure_scale());
+
+        let aperture = match &self.aperture_shape {
+            ApertureDesc::Circle => Aperture::Circle,
+            ApertureDesc::Polygon { blades, rotation } => Aperture::Polygon {
+                blades: *blades,
+                rotation: *rotation,
+            },
+            ApertureDesc::Mask(path) => {
+                let path = base.join(path).to_string_lossy().into_owned();
+                let image =
+                    image::open(&path).map_err(|e| SceneError::Image(path.clone(), e.into()))?;
+                let mask = ApertureMask::new(&image).ok_or(SceneError::EmptyApertureMask(path))?;
+
+                Aperture::Mask(Arc::new(mask))
+            }
+        };
+
+        Ok(Camera::animated(keys, aspect_ratio, exposure)
+            .with_rolling_shutter(self.readout)
+            .with_projection(self.projection)
+            .with_exposure_scale(exposure_scale)
+            .with_aperture(aperture)
+            .with_cat_eye(self.cat_eye))
+    }
+}
+
+#[derive(Deserialize, Default)]
+pub enum ApertureDesc {
+    #[default]
+    Circle,
+    /// Rotation in degrees.
+    Polygon {
+        blades: u32,
+        #[serde(default)]
+        rotation: f32,
+    },
+    /// Path of a grayscale image.
+    Mask(String),
+}
+
+fn default_vfov() -> Animated<f32> {
+    Animated::Constant(90.)
+}
+
+fn default_up() -> Vec3 {
+    Vec3::new(0., 1., 0.)
+}
+
+fn default_vup() -> Animated<Vec3> {
+    Animated::Constant(default_up())
+}
+
+#[derive(Deserialize)]
+pub enum BackgroundDesc {
+    Solid(Vec3),
+    /// Blends from `bottom` to `top` by the y component of the ray direction.
+    Gradient {
+        top: Vec3,
+        bottom: Vec3,
+    },
+    /// Latitude-longitude environment map, with `u` going around the y axis from -z and
+    /// `v` up. Matches renders with the `Equirectangular` projection from a camera looking
+    /// down -z.
+    Environment(TextureDesc),
+}
+
+#[derive(Deserialize)]
+pub enum TextureDesc {
+    Solid(Animated<Vec3>),
+    Checker {
+        even: Box<TextureDesc>,
+        odd: Box<TextureDesc>,
+    },
+    Noise {
+        scale: f32,
+    },
+    Turbulence {
+        scale: f32,
+        depth: usize,
+    },
+    Marbled {
+        scale: f32,
+    },
+    Image {
+        /// Relative to the scene file.
+        path: String,
+        /// Defaults to linear for .hdr and .exr files and sRGB otherwise.
+        #[serde(default)]
+        color_space: Option<ColorSpace>,
+        #[serde(default)]
+        filter: Filter,
+        #[serde(default)]
+        wrap: Wrap,
+    },
+    /// Looks up `texture` at transformed texture coordinates, see `texture::transform_uv`.
+    Transformed {
+        texture: Box<TextureDesc>,
+        uv: UvTransform,
+    },
+    Named(String),
+}
+
+#[derive(Deserialize)]
+pub enum MaterialDesc {
+    Empty,
+    Lambertian {
+        albedo: TextureDesc,
+    },
+    Metal {
+        albedo: TextureDesc,
+        #[serde(default)]
+        fuzz: Animated<f32>,
+    },
+    Dielectric {
+        #[serde(default = "default_white")]
+        albedo: TextureDesc,
+        #[serde(default)]
+        fuzz: Animated<f32>,
+        ior: Animated<f32>,
+    },
+    DiffuseLight {
+        emit: TextureDesc,
+        #[serde(default = "default_intensity")]
+        intensity: Animated<f32>,
+    },
+    Isotropic {
+        albedo: Animated<Vec3>,
+    },
+}
+
+impl TextureDesc {
+    fn sort_keys(&mut self) -> Result<(), SceneError> {
+        match self {
+            TextureDesc::Solid(c) => c.sort_keys(),
+            TextureDesc::Checker { even, odd } => {
+                even.sort_keys()?;
+                odd.sort_keys()
+            }
+            TextureDesc::Transformed { texture, .. } => texture.sort_keys(),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl MaterialDesc {
+    fn sort_keys(&mut self) -> Result<(), SceneError> {
+        match self {
+            MaterialDesc::Empty => Ok(()),
+            MaterialDesc::Lambertian { albedo } => albedo.sort_keys(),
+            MaterialDesc::Metal { albedo, fuzz } => {
+                albedo.sort_keys()?;
+                fuzz.sort_keys()
+            }
+            MaterialDesc::Dielectric { albedo, fuzz, ior } => {
+                albedo.sort_keys()?;
+                fuzz.sort_keys()?;
+                ior.sort_keys()
+            }
+            MaterialDesc::DiffuseLight { emit, intensity } => {
+                emit.sort_keys()?;
+                intensity.sort_keys()
+            }
+            MaterialDesc::Isotropic { albedo } => albedo.sort_keys(),
+        }
+    }
+}
+
+fn default_white() -> TextureDesc {
+    TextureDesc::Solid(Animated::Constant(Vec3::from(1.)))
+}
+
+fn default_intensity() -> Animated<f32> {
+    Animated::Constant(1.)
+}
+
+#[derive(Deserialize)]
+pub enum TransformDesc {
+    /// Euler angles in radians, see `Transform::rotate`.
+    Rotate(f32, f32, f32),
+    /// Axis and angle in radians.
+    RotateAxis(Vec3, f32),
+    /// Quaternion `(w, x, y, z)`, normalized on load.
+    Quaternion(f32, f32, f32, f32),
+    Translate(Vec3),
+    Scale(Vec3),
+    /// Places the local z axis from `from` towards `to`.
+    LookAt {
+        from: Vec3,
+        to: Vec3,
+        #[serde(default = "default_up")]
+        up: Vec3,
+    },
+}
+
+#[derive(Deserialize)]
+pub enum ObjectDesc {
+    Sphere {
+        center: Vec3,
+        radius: f32,
+        material: String,
+    },
+    Triangle {
+        vertices: (Vec3, Vec3, Vec3),
+        /// Defaults to the face normal.
+        #[serde(default)]
+        normals: Option<(Vec3, Vec3, Vec3)>,
+        material: String,
+    },
+    /// Wavefront OBJ, path relative to the scene file. Transforms compose like a matrix
+    /// product, the last one is applied first: `[Translate(..), Scale(..)]` scales, then
+    /// translates.
+    Mesh {
+        path: String,
+        #[serde(default)]
+        transform: Vec<TransformDesc>,
+        material: String,
+    },
+    Medium {
+        boundary: Box<ObjectDesc>,
+        density: f32,
+        albedo: Vec3,
+    },
+    Moving {
+        object: Box<ObjectDesc>,
+        velocity: Vec3,
+    },
+    /// Moves the object between keyframed transforms during the exposure.
+    Motion {
+        object: Box<ObjectDesc>,
+        keyframes: Vec<KeyframeDesc>,
+    },
+    /// A named prototype placed by the transforms, composed like `Mesh` transforms with the
+    /// last one applied first.
+    Instance {
+        prototype: String,
+        #[serde(default)]
+        transform: Vec<TransformDesc>,
+    },
+}
+
+#[derive(Deserialize)]
+pub struct KeyframeDesc {
+    pub time: f32,
+    /// Composed like `Instance` transforms, must not shear, see `Transform::shears`.
+    pub transform: Vec<TransformDesc>,
+}
+
+/// Colors in the file are taken to be in `working_space`, and image textures are converted
+/// into it. Meshes and images already in `assets` aren't read again.
+pub fn load(
+    path: impl AsRef<Path>,
+    aspect_ratio: f32,
+    exposure: Range<f32>,
+    working_space: WorkingSpace,
+    assets: &mut Assets,
+) -> Result<Scene, SceneError> {
+    let path = path.as_ref();
+    let mut desc: SceneDesc = ron::from_str(&std::fs::read_to_string(path)?)?;
+    desc.sort_keys()?;
+
+    desc.build(
+        path.parent().unwrap_or_else(|| Path::new(".")),
+        aspect_ratio,
+        exposure,
+        working_space,
+        assets,
+    )
+}
+
+struct Builder<'d> {
+    base: &'d Path,
+    assets: &'d mut Assets,
+    exposure: Range<f32>,
+    /// Animated values are evaluated at this time.
+    time: f32,
+    working_space: WorkingSpace,
+    rng: StdRng,
+    textures: BTreeMap<String, Texture>,
+    materials: BTreeMap<String, Material>,
+    prototypes: BTreeMap<String, Arc<dyn Hittable>>,
+}
+
+impl SceneDesc {
+    /// Sorts the keys of all animated values, see `Animated::sort_keys`.
+    pub fn sort_keys(&mut self) -> Result<(), SceneError> {
+        let camera = &mut self.camera;
+        camera.lookfrom.sort_keys()?;
+        camera.lookat.sort_keys()?;
+        camera.vup.sort_keys()?;
+        camera.vfov.sort_keys()?;
+        camera.aperture.sort_keys()?;
+
+        if let Some(focus_distance) = &mut camera.focus_distance {
+            focus_distance.sort_keys()?;
+        }
+
+        for desc in self.textures.values_mut() {
+            desc.sort_keys()?;
+        }
+
+        for desc in self.materials.values_mut() {
+            desc.sort_keys()?;
+        }
+
+        if let BackgroundDesc::Environment(desc) = &mut self.background {
+            desc.sort_keys()?;
+        }
+
+        Ok(())
+    }
+
+    /// Relative asset paths are resolved against `base`.
+    pub fn build(
+        &self,
+        base: &Path,
+        aspect_ratio: f32,
+        exposure: Range<f32>,
+        working_space: WorkingSpace,
+        assets: &mut Assets,
+    ) -> Result<Scene, SceneError> {
+        if self.objects.is_empty() {
+            return Err(SceneError::EmptyObject);
+        }
+
+        let time = exposure.start;
+
+        let camera = self.camera.build(base, aspect_ratio, exposure)?;
+
+        let mut builder = Builder {
+            base,
+            assets,
+            exposure: camera.exposure(),
+            time,
+            working_space,
+            rng: StdRng::seed_from_u64(self.seed),
+            textures: BTreeMap::new(),
+            materials: BTreeMap::new(),
+            prototypes: BTreeMap::new(),
+        };
+
+        for (name, desc) in &self.textures {
+            let t = builder.texture(desc)?;
+            builder.textures.insert(name.clone(), t);
+        }
+
+        for (name, desc) in &self.materials {
+            let m = builder.material(desc)?;
+            builder.materials.insert(name.clone(), m);
+        }
+
+        for (name, desc) in &self.prototypes {
+            let p = builder.object(desc)?;
+            builder.prototypes.insert(name.clone(), Arc::from(p));
+        }
+
+        // One hittable per described object, so that e.g. a mesh gets a single object ID
+        let world = self
+            .objects
+            .iter()
+            .map(|desc| builder.object(desc))
+            .collect::<Result<_, _>>()?;
+
+        let background: Background = match &self.background {
+            &BackgroundDesc::Solid(color) => Box::new(move |_| color),
+            &BackgroundDesc::Gradient { top, bottom } => Box::new(move |dir| {
+                let t = 0.5 * (dir.unit()[Y] + 1.);
+                t * top + (1. - t) * bottom
+            }),
+            BackgroundDesc::Environment(desc) => {
+                let texture = builder.texture(desc)?;
+
+                Box::new(move |dir| {
+                    let d = dir.unit();
+                    let u = 0.5 + d[X].atan2(-d[Z]) / (2. * PI);
+                    let v = 0.5 + d[Y].clamp(-1., 1.).asin() / PI;
+
+                    texture(&TexCoord::new(Vec3::new(u, v, 0.), d))
+                })
+            }
+        };
+
+        Ok((camera, world, background))
+    }
+}
+
+impl<'d> Builder<'d> {
+    fn path(&self, path: &str) -> String {
+        self.base.join(path).to_string_lossy().into_owned()
+    }
+
+    fn texture(&mut self, desc: &TextureDesc) -> Result<Texture, SceneError> {
+        Ok(match desc {
+            TextureDesc::Solid(c) => texture::solid(c.at(self.time)),
+            TextureDesc::Checker { even, odd } => {
+                texture::checker(self.texture(even)?, self.texture(odd)?)
+            }
+            TextureDesc::Noise { scale } => texture::perlin_noise(*scale, &mut self.rng),
+            TextureDesc::Turbulence { scale, depth } => {
+                texture::perlin_turb(*scale, *depth, &mut self.rng)
+            }
+            TextureDesc::Marbled { scale } => texture::marbled(*scale, &mut self.rng),
+            TextureDesc::Image {
+                path,
+                color_space,
+                filter,
+                wrap,
+            } => {
+                let path = self.path(path);
+
+                texture::image(
+                    path.clone(),
+                    color_space.unwrap_or_else(|| texture::default_color_space(&path)),
+                    self.working_space,
+                    Sampler {
+                        filter: *filter,
+                        wrap: *wrap,
+                    },
+                    self.assets,
+                )
+                .map_err(|e| SceneError::Image(path, e))?
+            }
+            TextureDesc::Transformed { texture, uv } => {
+                texture::transform_uv(self.texture(texture)?, *uv)
+            }
+            TextureDesc::Named(name) => self
+                .textures
+                .get(name)
+                .ok_or_else(|| SceneError::UnknownTexture(name.clone()))?
+                .clone(),
+        })
+    }
+
+    fn material(&mut self, desc: &MaterialDesc) -> Result<Material, SceneError> {
+        Ok(match desc {
+            MaterialDesc::Empty => Material::Empty,
+            MaterialDesc::Lambertian { albedo } => Material::Lambertian {
+                albedo: self.texture(albedo)?,
+            },
+            MaterialDesc::Metal { albedo, fuzz } => Material::Metal {
+                albedo: self.texture(albedo)?,
+                fuzz: fuzz.at(self.time),
+            },
+            MaterialDesc::Dielectric { albedo, fuzz, ior } => Material::Dielectric {
+                albedo: self.texture(albedo)?,
+                fuzz: fuzz.at(self.time),
+                ior: ior.at(self.time),
+            },
+            MaterialDesc::DiffuseLight { emit, intensity } => Material::DiffuseLight {
+                emit: self.texture(emit)?,
+                intensity: intensity.at(self.time),
+            },
+            MaterialDesc::Isotropic { albedo } => Material::Isotropic {
+                albedo: albedo.at(self.time),
+            },
+        })
+    }
+
+    fn named_material(&self, name: &str) -> Result<Material, SceneError> {
+        self.materials
+            .get(name)
+            .cloned()
+            .ok_or_else(|| SceneError::UnknownMaterial(name.to_string()))
+    }
+
+    /// Builds a BVH over `objs`, reporting how long it took like the mesh loader does.
+    fn bvh(&self, objs: Vec<Box<dyn Hittable>>) -> Bvh {
+        let bvh = Bvh::new(objs, self.exposure.clone());
+        eprintln!("bvh: {}", bvh.stats());
+        bvh
+    }
+
+    /// Marks hits on `objs` with the ID of material `name`, numbered from one in name order.
+    fn with_material_id(
+        &self,
+        name: &str,
+        mut objs: Vec<Box<dyn Hittable>>,
+    ) -> Vec<Box<dyn Hittable>> {
+        let id = match self.materials.keys().position(|k| k == name) {
+            Some(i) => i as u32 + 1,
+            None => return objs,
+        };
+
+        let object: Box<dyn Hittable> = match objs.len() {
+            0 => return objs,
+            1 => objs.pop().unwrap(),
+            _ => Box::new(self.bvh(objs)),
+        };
+
+        vec![Box::new(MaterialTagged { object, id })]
+    }
+
+    fn objects(&mut self, desc: &ObjectDesc) -> Result<Vec<Box<dyn Hittable>>, SceneError> {
+        Ok(match desc {
+            ObjectDesc::Sphere {
+                center,
+                radius,
+                material,
+            } => self.with_material_id(
+                material,
+                vec![Box::new(Sphere {
+                    center: *center,
+                    radius: *radius,
+                    material: self.named_material(material)?,
+                })],
+            ),
+            ObjectDesc::Triangle {
+                vertices,
+                normals,
+                material,
+            } => {
+                let (v1, v2, v3) = *vertices;
+                let normals = normals.unwrap_or_else(|| {
+                    let n = Vec3::cross(v3 - v1, v2 - v1);
+                    (n, n, n)
+                });
+
+                self.with_material_id(
+                    material,
+                    vec![Box::new(Triangle {
+                        vertices: *vertices,
+                        normals,
+                        material: self.named_material(material)?,
+                    })],
+                )
+            }
+            ObjectDesc::Mesh {
+                path,
+                transform,
+                material,
+            } => {
+                let path = self.path(path);
+
+                let triangles = Mesh::load(
+                    path.clone(),
+                    &transform_stack(transform)?,
+                    self.named_material(material)?,
+                    self.assets,
+                )
+                .map_err(|e| SceneError::Mesh(path, e))?;
+
+                self.with_material_id(material, triangles)
+            }
+            ObjectDesc::Medium {
+                boundary,
+                density,
+                albedo,
+            } => vec![Box::new(ConstantMedium::new(
+                self.object(boundary)?,
+                Material::Isotropic { albedo: *albedo },
+                *density,
+            ))],
+            ObjectDesc::Moving { object, velocity } => vec![Box::new(LinearMove {
+                object: self.object(object)?,
+                velocity: *velocity,
+            })],
+            ObjectDesc::Motion { object, keyframes } => {
+                if keyframes.is_empty() {
+                    return Err(SceneError::EmptyKeys);
+                }
+
+                if let Some(k) = keyframes.iter().find(|k| !k.time.is_finite()) {
+                    return Err(SceneError::InvalidKeyTime(k.time));
+                }
+
+                vec![Box::new(MotionTransform::new(
+                    self.object(object)?,
+                    keyframes
+                        .iter()
+                        .map(keyframe)
+                        .collect::<Result<_, SceneError>>()?,
+                ))]
+            }
+            ObjectDesc::Instance {
+                prototype,
+                transform,
+            } => {
+                let object = self
+                    .prototypes
+                    .get(prototype)
+                    .ok_or_else(|| SceneError::UnknownPrototype(prototype.clone()))?
+                    .clone();
+
+                vec![Box::new(Instance::new(object, transform_stack(transform)?))]
+            }
+        })
+    }
+
+    /// Builds `desc` as a single hittable, wrapping multiple objects in a BVH.
+    fn object(&mut self, desc: &ObjectDesc) -> Result<Box<dyn Hittable>, SceneError> {
+        let mut objs = self.objects(desc)?;
+
+        match objs.len() {
+            0 => Err(SceneError::EmptyObject),
+            1 => Ok(objs.pop().unwrap()),
+            _ => Ok(Box::new(self.bvh(objs))),
+        }
+    }
+}
+
+/// Fails for sheared transforms, which can't be split into the parts that are interpolated.
+fn keyframe(desc: &KeyframeDesc) -> Result<Keyframe, SceneError> {
+    let transform = transform_stack(&desc.transform)?;
+
+    if transform.shears() {
+        return Err(SceneError::ShearedKeyframe(desc.time));
+    }
+
+    Ok(Keyframe::new(desc.time, &transform))
+}
+
+/// Fails for transforms that can't be inverted, such as a zero scale, or that aren't finite,
+/// such as a rotation about a zero axis.
+fn transform_stack(descs: &[TransformDesc]) -> Result<Transform, SceneError> {
+    let transforms: Vec<Transform> = descs
+        .iter()
+        .map(|t| match t {
+            TransformDesc::Rotate(a, b, c) => Transform::rotate(*a, *b, *c),
+            TransformDesc::RotateAxis(axis, angle) => Transform::rotate_axis(*axis, *angle),
+            TransformDesc::Quaternion(w, x, y, z) => Transform::rotation(Quat::new(*w, *x, *y, *z)),
+            TransformDesc::Translate(t) => Transform::translate(*t),
+            TransformDesc::Scale(s) => Transform::scale(*s),
+            TransformDesc::LookAt { from, to, up } => Transform::look_at(*from, *to, *up),
+        })
+        .collect();
+
+    let transform = Transform::stack(transforms.iter());
+    let finite = |m: &Mat4| m.0.iter().all(|v| v.is_finite());
+
+    if !finite(transform.matrix())
+        || !finite(transform.inverse_matrix())
+        || transform.matrix().inv().is_none()
+    {
+        return Err(SceneError::SingularTransform);
+    }
+
+    Ok(transform)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keyed(keys: &[(f32, f32)]) -> Animated<f32> {
+        let mut a = Animated::Keyed(keys.to_vec());
+        a.sort_keys().unwrap();
+        a
+    }
+
+    #[test]
+    fn constant_ignores_time() {
+        assert_eq!(Animated::Constant(3.).at(-10.), 3.);
+    }
+
+    #[test]
+    fn keys_interpolate_and_hold() {
+        let a = keyed(&[(0., 1.), (2., 3.)]);
+
+        assert_eq!(a.at(-1.), 1.);
+        assert_eq!(a.at(1.), 2.);
+        assert_eq!(a.at(5.), 3.);
+    }
+
+    #[test]
+    fn unsorted_keys_are_sorted() {
+        let a = keyed(&[(2., 3.), (0., 1.), (1., 0.)]);
+
+        assert_eq!(a.at(0.5), 0.5);
+        assert_eq!(a.at(1.5), 1.5);
+    }
+
+    #[test]
+    fn equal_times_step() {
+        let a = keyed(&[(0., 0.), (1., 1.), (1., 5.), (2., 5.)]);
+
+        assert_eq!(a.at(0.5), 0.5);
+        assert_eq!(a.at(1.), 5.);
+        assert!(a.at(1.5).is_finite());
+    }
+
+    #[test]
+    fn empty_keys_are_rejected() {
+        let mut a: Animated<f32> = Animated::Keyed(vec![]);
+        assert!(matches!(a.sort_keys(), Err(SceneError::EmptyKeys)));
+    }
+
+    #[test]
+    fn singular_transforms_are_rejected() {
+        for desc in [
+            TransformDesc::Scale(Vec3::new(0., 4., 4.)),
+            TransformDesc::RotateAxis(Vec3::from(0.), 1.),
+            TransformDesc::Quaternion(0., 0., 0., 0.),
+        ] {
+            let stack = [TransformDesc::Translate(Vec3::from(1.)), desc];
+            assert!(matches!(
+                transform_stack(&stack),
+                Err(SceneError::SingularTransform)
+            ));
+        }
+
+        assert!(transform_stack(&[TransformDesc::Scale(Vec3::new(-1., 2., 3.))]).is_ok());
+    }
+
+    #[test]
+    fn sheared_keyframes_are_rejected() {
+        let key = |transform| KeyframeDesc {
+            time: 1.,
+            transform,
+        };
+
+        let sheared = key(vec![
+            TransformDesc::Scale(Vec3::new(2., 1., 1.)),
+            TransformDesc::Rotate(0.5, 0., 0.),
+        ]);
+        assert!(matches!(
+            keyframe(&sheared),
+            Err(SceneError::ShearedKeyframe(t)) if t == 1.
+        ));
+
+        let rigid = key(vec![
+            TransformDesc::Translate(Vec3::new(1., 2., 3.)),
+            TransformDesc::Rotate(0.5, 0., 0.),
+            TransformDesc::Scale(Vec3::new(-2., 1., 3.)),
+        ]);
+        assert!(keyframe(&rigid).is_ok());
+    }
+
+    #[test]
+    fn empty_scenes_are_rejected() {
+        let desc: SceneDesc = ron::from_str(
+            "(camera: (lookfrom: (0., 0., 1.), lookat: (0., 0., 0.)), \
+             background: Solid((0., 0., 0.)), objects: [])",
+        )
+        .unwrap();
+
+        assert!(matches!(
+            desc.build(
+                Path::new("."),
+                1.,
+                0.0..1.,
+                WorkingSpace::Rec709,
+                &mut Assets::default()
+            ),
+            Err(SceneError::EmptyObject)
+        ));
+    }
+
+    #[test]
+    fn nan_times_are_rejected() {
+        let mut a = Animated::Keyed(vec![(0., 1.), (f32::NAN, 2.)]);
+        assert!(matches!(a.sort_keys(), Err(SceneError::InvalidKeyTime(_))));
+    }
+}
@@ -1,23 +1,76 @@
+use crate::assets::Assets;
+use crate::color::{ColorSpace, WorkingSpace};
 use crate::math::Vec3;
 use crate::perlin::Perlin;
-use image::GenericImageView;
+use image::codecs::hdr::HdrDecoder;
 use rand::Rng;
+use serde::Deserialize;
+use std::error::Error;
+use std::f32::consts::PI;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
 use std::sync::Arc;
 
-pub type Texture = Arc<dyn Fn(Vec3, Vec3) -> Vec3 + Send + Sync>;
+pub type Texture = Arc<dyn Fn(&TexCoord) -> Vec3 + Send + Sync>;
+
+/// Where a texture is looked up.
+#[derive(Debug, Copy, Clone)]
+pub struct TexCoord {
+    pub uv: Vec3,
+    /// Point in space, for solid textures.
+    pub p: Vec3,
+    /// Change of `uv` and `p` from one pixel to the next horizontally and vertically, zero
+    /// for point lookups.
+    pub duvdx: Vec3,
+    pub duvdy: Vec3,
+    pub dpdx: Vec3,
+    pub dpdy: Vec3,
+}
+
+impl TexCoord {
+    /// Lookup without a footprint.
+    pub fn new(uv: Vec3, p: Vec3) -> Self {
+        TexCoord {
+            uv,
+            p,
+            duvdx: Vec3::from(0.),
+            duvdy: Vec3::from(0.),
+            dpdx: Vec3::from(0.),
+            dpdy: Vec3::from(0.),
+        }
+    }
+
+    /// Width of the footprint in texture coordinates.
+    pub fn uv_width(&self) -> f32 {
+        self.duvdx.len().max(self.duvdy.len())
+    }
+
+    /// Width of the footprint in space.
+    pub fn p_width(&self) -> f32 {
+        self.dpdx.len().max(self.dpdy.len())
+    }
+}
 
 pub fn solid(color: Vec3) -> Texture {
-    Arc::new(move |_, _| color)
+    Arc::new(move |_| color)
 }
 
+/// Alternates between `even` and `odd` in cells of size π/10. Where a pixel covers more
+/// than half a cell, both are blended towards their average instead of aliasing.
 pub fn checker(even: Texture, odd: Texture) -> Texture {
-    Arc::new(move |uv, p| {
+    Arc::new(move |c| {
+        let p = c.p;
         let t = (10. * p.x()).sin() * (10. * p.y()).sin() * (10. * p.z()).sin();
+        let point = if t < 0. { odd(c) } else { even(c) };
+
+        let cells = c.p_width() * 10. / PI;
+        let blend = (2. * cells - 1.).clamp(0., 1.);
 
-        if t < 0. {
-            odd(uv, p)
+        if blend > 0. {
+            (1. - blend) * point + blend * 0.5 * (even(c) + odd(c))
         } else {
-            even(uv, p)
+            point
         }
     })
 }
@@ -25,37 +78,511 @@ pub fn checker(even: Texture, odd: Texture) -> Texture {
 pub fn perlin_noise(scale: f32, rng: &mut impl Rng) -> Texture {
     let perlin: Perlin<256> = Perlin::new(rng);
 
-    Arc::new(move |_, p| Vec3::from(0.5 * (1.0 + perlin.noise(scale * p))))
+    Arc::new(move |c| Vec3::from(0.5 * (1.0 + perlin.noise(scale * c.p))))
 }
 
 pub fn perlin_turb(scale: f32, depth: usize, rng: &mut impl Rng) -> Texture {
     let perlin: Perlin<256> = Perlin::new(rng);
 
-    Arc::new(move |_, p| Vec3::from(perlin.turb(scale * p, depth)))
+    Arc::new(move |c| Vec3::from(perlin.turb(scale * c.p, depth)))
 }
 
 pub fn marbled(scale: f32, rng: &mut impl Rng) -> Texture {
     let perlin: Perlin<256> = Perlin::new(rng);
 
-    Arc::new(move |_, p| Vec3::from(0.5 * (1.0 + (scale * p.z() + 10. * perlin.turb(p, 7)).sin())))
+    Arc::new(move |c| Vec3::from(0.5 * (1.0 + (scale * c.p.z() + 10. * perlin.turb(c.p, 7)).sin())))
 }
 
-pub fn image(path: String) -> Texture {
-    let img = image::io::Reader::open(&path)
-        .expect(format!("Image {} not found", path).as_str())
-        .decode()
-        .unwrap();
+/// Image whose pixels are stored in `space`, decoded into `working` space once at load.
+/// `(0, 0)` is the bottom left and `(1, 1)` the top right corner of the image.
+///
+/// Pixels are kept as floats, so Radiance HDR and OpenEXR images keep their full range and
+/// 16-bit images their precision.
+pub fn image(
+    path: String,
+    space: ColorSpace,
+    working: WorkingSpace,
+    sampler: Sampler,
+    assets: &mut Assets,
+) -> Result<Texture, Box<dyn Error>> {
+    let image = assets.image(&path)?;
+
+    let mut levels = vec![Level {
+        width: image.width,
+        height: image.height,
+        pixels: image
+            .pixels
+            .iter()
+            .map(|&c| space.decode(c, working))
+            .collect(),
+    }];
+
+    if sampler.filter == Filter::Trilinear {
+        while let Some(next) = levels[levels.len() - 1].downsample() {
+            levels.push(next);
+        }
+    }
 
-    Arc::new(move |uv, _| {
-        Vec3::from(img.get_pixel(
-            (uv.x() * (img.dimensions().0 - 1) as f32) as u32,
-            ((1. - uv.y()) * (img.dimensions().1 - 1) as f32) as u32,
-        ))
+    let image = ImageTexture { levels, sampler };
+
+    Ok(Arc::new(move |c| image.sample(c.uv, c.uv_width())))
+}
+
+/// Texture looked up at `uv` scaled, then rotated counterclockwise about the origin and
+/// finally offset by `transform`.
+pub fn transform_uv(texture: Texture, transform: UvTransform) -> Texture {
+    let (sin, cos) = (transform.rotation / 180. * PI).sin_cos();
+
+    let linear = move |d: Vec3| {
+        let (u, v) = (d.x() * transform.scale.0, d.y() * transform.scale.1);
+        Vec3::new(cos * u - sin * v, sin * u + cos * v, d.z())
+    };
+
+    Arc::new(move |c| {
+        texture(&TexCoord {
+            uv: linear(c.uv) + Vec3::new(transform.offset.0, transform.offset.1, 0.),
+            duvdx: linear(c.duvdx),
+            duvdy: linear(c.duvdy),
+            ..*c
+        })
     })
 }
 
+#[derive(Debug, Copy, Clone, Deserialize)]
+#[serde(default)]
+pub struct UvTransform {
+    pub scale: (f32, f32),
+    pub offset: (f32, f32),
+    /// Degrees.
+    pub rotation: f32,
+}
+
+impl Default for UvTransform {
+    fn default() -> Self {
+        UvTransform {
+            scale: (1., 1.),
+            offset: (0., 0.),
+            rotation: 0.,
+        }
+    }
+}
+
+/// How image textures are looked up between and outside of their pixels.
+#[derive(Debug, Copy, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct Sampler {
+    pub filter: Filter,
+    pub wrap: Wrap,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Default, Deserialize)]
+pub enum Filter {
+    /// The pixel the lookup falls on.
+    Nearest,
+    /// Linear interpolation between the four nearest pixels, whatever the lookup footprint.
+    Bilinear,
+    /// Bilinear lookups in the two mipmap levels closest to the lookup footprint, blended
+    /// linearly. Same as `Bilinear` where the footprint is unknown.
+    #[default]
+    Trilinear,
+}
+
+/// What texture coordinates outside [0, 1] refer to.
+#[derive(Debug, Copy, Clone, PartialEq, Default, Deserialize)]
+pub enum Wrap {
+    /// Tiles the image.
+    #[default]
+    Repeat,
+    /// Extends the edge pixels.
+    Clamp,
+    /// Tiles the image flipping every other copy, which hides the seams.
+    Mirror,
+}
+
+impl Wrap {
+    fn index(self, i: i64, n: usize) -> usize {
+        let n = n as i64;
+
+        let i = match self {
+            Wrap::Repeat => i.rem_euclid(n),
+            Wrap::Clamp => i.clamp(0, n - 1),
+            Wrap::Mirror => {
+                let i = i.rem_euclid(2 * n);
+                if i < n {
+                    i
+                } else {
+                    2 * n - 1 - i
+                }
+            }
+        };
+
+        i as usize
+    }
+}
+
+struct ImageTexture {
+    /// Mipmap levels from the full resolution down to a single pixel, or only the full
+    /// resolution if they aren't needed.
+    levels: Vec<Level>,
+    sampler: Sampler,
+}
+
+impl ImageTexture {
+    /// Value at `uv` filtered over `footprint`, the width of the lookup in texture
+    /// coordinates.
+    fn sample(&self, uv: Vec3, footprint: f32) -> Vec3 {
+        let wrap = self.sampler.wrap;
+        let base = &self.levels[0];
+
+        match self.sampler.filter {
+            Filter::Nearest => base.nearest(uv, wrap),
+            Filter::Bilinear => base.bilinear(uv, wrap),
+            Filter::Trilinear => {
+                let texels = footprint * base.width.max(base.height) as f32;
+                let lod = texels.max(1.).log2().min((self.levels.len() - 1) as f32);
+
+                let i = lod.floor() as usize;
+                let t = lod - i as f32;
+                let fine = self.levels[i].bilinear(uv, wrap);
+
+                if t > 0. {
+                    (1. - t) * fine + t * self.levels[i + 1].bilinear(uv, wrap)
+                } else {
+                    fine
+                }
+            }
+        }
+    }
+}
+
+/// Pixels in row-major order from the top left.
+pub(crate) struct Level {
+    width: usize,
+    height: usize,
+    pixels: Vec<Vec3>,
+}
+
+impl Level {
+    fn texel(&self, x: i64, y: i64, wrap: Wrap) -> Vec3 {
+        self.pixels[wrap.index(y, self.height) * self.width + wrap.index(x, self.width)]
+    }
+
+    fn nearest(&self, uv: Vec3, wrap: Wrap) -> Vec3 {
+        let x = (uv.x() * self.width as f32).floor() as i64;
+        let y = ((1. - uv.y()) * self.height as f32).floor() as i64;
+
+        self.texel(x, y, wrap)
+    }
+
+    fn bilinear(&self, uv: Vec3, wrap: Wrap) -> Vec3 {
+        // Relative to pixel centers
+        let x = uv.x() * self.width as f32 - 0.5;
+        let y = (1. - uv.y()) * self.height as f32 - 0.5;
+        let (x0, y0) = (x.floor(), y.floor());
+        let (tx, ty) = (x - x0, y - y0);
+        let (x0, y0) = (x0 as i64, y0 as i64);
+
+        let top = (1. - tx) * self.texel(x0, y0, wrap) + tx * self.texel(x0 + 1, y0, wrap);
+        let bottom =
+            (1. - tx) * self.texel(x0, y0 + 1, wrap) + tx * self.texel(x0 + 1, y0 + 1, wrap);
+
+        (1. - ty) * top + ty * bottom
+    }
+
+    /// Next smaller mipmap level, halving each side rounded down, or `None` for a single
+    /// pixel. See `downsample_taps` for the filter.
+    fn downsample(&self) -> Option<Level> {
+        if self.width == 1 && self.height == 1 {
+            return None;
+        }
+
+        let (width, height) = ((self.width / 2).max(1), (self.height / 2).max(1));
+        let pixels = (0..height)
+            .flat_map(|y| (0..width).map(move |x| (x, y)))
+            .map(|(x, y)| {
+                let (xs, ys) = (
+                    downsample_taps(x, self.width),
+                    downsample_taps(y, self.height),
+                );
+
+                ys.iter()
+                    .flat_map(|&(sy, wy)| {
+                        xs.iter()
+                            .map(move |&(sx, wx)| wx * wy * self.texel(sx, sy, Wrap::Clamp))
+                    })
+                    .sum()
+            })
+            .collect();
+
+        Some(Level {
+            width,
+            height,
+            pixels,
+        })
+    }
+}
+
+/// Source pixels and their weights for pixel `i` of a side `n` pixels long halved by
+/// `Level::downsample`. Even sides average pairs. Odd sides use three pixels weighted by how
+/// much of each the larger output pixel covers, so that the last row or column isn't dropped.
+fn downsample_taps(i: usize, n: usize) -> [(i64, f32); 3] {
+    let first = 2 * i as i64;
+
+    if n == 1 {
+        [(0, 1.), (0, 0.), (0, 0.)]
+    } else if n % 2 == 1 {
+        let (half, i, n) = ((n / 2) as f32, i as f32, n as f32);
+        [
+            (first, (half - i) / n),
+            (first + 1, half / n),
+            (first + 2, (i + 1.) / n),
+        ]
+    } else {
+        [(first, 0.5), (first + 1, 0.5), (first, 0.)]
+    }
+}
+
+/// Color space that the pixels of the image at `path` are most likely stored in: linear for
+/// the float formats and sRGB otherwise.
+pub fn default_color_space(path: &str) -> ColorSpace {
+    match float_format(path) {
+        Some(_) => ColorSpace::Linear,
+        None => ColorSpace::Srgb,
+    }
+}
+
+#[derive(Copy, Clone)]
+enum FloatFormat {
+    Hdr,
+    Exr,
+}
+
+fn float_format(path: &str) -> Option<FloatFormat> {
+    let ext = Path::new(path)
+        .extension()
+        .map(|e| e.to_string_lossy().to_lowercase());
+
+    match ext.as_deref() {
+        Some("hdr") => Some(FloatFormat::Hdr),
+        Some("exr") => Some(FloatFormat::Exr),
+        _ => None,
+    }
+}
+
+/// Pixels of the image at `path`, normalized to [0, 1] for integer formats.
+pub(crate) fn load_image(path: &str) -> Result<Level, Box<dyn Error>> {
+    match float_format(path) {
+        Some(FloatFormat::Hdr) => {
+            let decoder = HdrDecoder::new(BufReader::new(File::open(path)?))?;
+            let meta = decoder.metadata();
+            let pixels = decoder
+                .read_image_hdr()?
+                .into_iter()
+                .map(|p| Vec3::new(p[0], p[1], p[2]))
+                .collect();
+
+            Ok(Level {
+                width: meta.width as usize,
+                height: meta.height as usize,
+                pixels,
+            })
+        }
+        Some(FloatFormat::Exr) => {
+            let image = exr::prelude::read_first_rgba_layer_from_file(
+                path,
+                |size, _| (size.width(), vec![Vec3::from(0.); size.area()]),
+                |(width, pixels), pos, (r, g, b, _): (f32, f32, f32, f32)| {
+                    pixels[pos.y() * *width + pos.x()] = Vec3::new(r, g, b)
+                },
+            )?;
+            let size = image.layer_data.size;
+            let (_, pixels) = image.layer_data.channel_data.pixels;
+
+            Ok(Level {
+                width: size.width(),
+                height: size.height(),
+                pixels,
+            })
+        }
+        // 8-bit images are widened exactly, so this keeps the precision of both
+        None => {
+            let img = image::open(path)?.into_rgba16();
+            let pixels = img
+                .pixels()
+                .map(|p| Vec3::new(p[0] as f32, p[1] as f32, p[2] as f32) / u16::MAX as f32)
+                .collect();
+
+            Ok(Level {
+                width: img.width() as usize,
+                height: img.height() as usize,
+                pixels,
+            })
+        }
+    }
+}
+
 impl From<Vec3> for Texture {
     fn from(c: Vec3) -> Self {
         solid(c)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(a: Vec3, b: Vec3) {
+        assert!((a - b).len() < 1e-5, "{:?} != {:?}", a, b);
+    }
+
+    /// 2×2 image whose pixels are 0 and 1 on the top row and 2 and 3 on the bottom row.
+    fn level() -> Level {
+        Level {
+            width: 2,
+            height: 2,
+            pixels: (0..4).map(|i| Vec3::from(i as f32)).collect(),
+        }
+    }
+
+    fn row(values: &[f32]) -> Level {
+        Level {
+            width: values.len(),
+            height: 1,
+            pixels: values.iter().map(|&v| Vec3::from(v)).collect(),
+        }
+    }
+
+    fn mean(level: &Level) -> Vec3 {
+        level.pixels.iter().copied().sum::<Vec3>() / level.pixels.len() as f32
+    }
+
+    #[test]
+    fn wrap_index_in_range() {
+        for wrap in [Wrap::Repeat, Wrap::Clamp, Wrap::Mirror] {
+            for i in 0..4 {
+                assert_eq!(wrap.index(i, 4), i as usize);
+            }
+        }
+    }
+
+    #[test]
+    fn wrap_index_repeat() {
+        assert_eq!(Wrap::Repeat.index(-1, 4), 3);
+        assert_eq!(Wrap::Repeat.index(-5, 4), 3);
+        assert_eq!(Wrap::Repeat.index(4, 4), 0);
+        assert_eq!(Wrap::Repeat.index(9, 4), 1);
+    }
+
+    #[test]
+    fn wrap_index_clamp() {
+        assert_eq!(Wrap::Clamp.index(-1, 4), 0);
+        assert_eq!(Wrap::Clamp.index(i64::MIN, 4), 0);
+        assert_eq!(Wrap::Clamp.index(4, 4), 3);
+        assert_eq!(Wrap::Clamp.index(i64::MAX, 4), 3);
+    }
+
+    #[test]
+    fn wrap_index_mirror() {
+        assert_eq!(Wrap::Mirror.index(-1, 4), 0);
+        assert_eq!(Wrap::Mirror.index(-2, 4), 1);
+        assert_eq!(Wrap::Mirror.index(4, 4), 3);
+        assert_eq!(Wrap::Mirror.index(7, 4), 0);
+        assert_eq!(Wrap::Mirror.index(8, 4), 0);
+        assert_eq!(Wrap::Mirror.index(-9, 4), 0);
+    }
+
+    #[test]
+    fn bilinear_hits_pixel_centers() {
+        let level = level();
+
+        assert_close(
+            level.bilinear(Vec3::new(0.25, 0.75, 0.), Wrap::Clamp),
+            Vec3::from(0.),
+        );
+        assert_close(
+            level.bilinear(Vec3::new(0.75, 0.75, 0.), Wrap::Clamp),
+            Vec3::from(1.),
+        );
+        assert_close(
+            level.bilinear(Vec3::new(0.25, 0.25, 0.), Wrap::Clamp),
+            Vec3::from(2.),
+        );
+        assert_close(
+            level.bilinear(Vec3::new(0.75, 0.25, 0.), Wrap::Clamp),
+            Vec3::from(3.),
+        );
+    }
+
+    #[test]
+    fn bilinear_interpolates_between_centers() {
+        let level = level();
+
+        assert_close(
+            level.bilinear(Vec3::new(0.5, 0.5, 0.), Wrap::Clamp),
+            Vec3::from(1.5),
+        );
+        assert_close(
+            level.bilinear(Vec3::new(0.5, 0.75, 0.), Wrap::Clamp),
+            Vec3::from(0.5),
+        );
+        assert_close(
+            level.bilinear(Vec3::new(0.25, 0.5, 0.), Wrap::Clamp),
+            Vec3::from(1.),
+        );
+    }
+
+    #[test]
+    fn bilinear_wraps_at_edges() {
+        let level = level();
+        let corner = Vec3::new(0., 1., 0.);
+
+        assert_close(level.bilinear(corner, Wrap::Clamp), Vec3::from(0.));
+        assert_close(level.bilinear(corner, Wrap::Repeat), Vec3::from(1.5));
+        assert_close(level.bilinear(corner, Wrap::Mirror), Vec3::from(0.));
+        assert_close(
+            level.bilinear(Vec3::new(1.25, -0.25, 0.), Wrap::Repeat),
+            level.bilinear(Vec3::new(0.25, 0.75, 0.), Wrap::Repeat),
+        );
+    }
+
+    #[test]
+    fn downsample_averages_pairs() {
+        let next = level().downsample().unwrap();
+
+        assert_eq!((next.width, next.height), (1, 1));
+        assert_close(next.pixels[0], Vec3::from(1.5));
+    }
+
+    #[test]
+    fn downsample_odd_sizes_keep_every_pixel() {
+        let next = row(&[0., 0., 9.]).downsample().unwrap();
+
+        assert_eq!((next.width, next.height), (1, 1));
+        assert_close(next.pixels[0], Vec3::from(3.));
+
+        let level = row(&[1., 2., 3., 4., 10.]);
+        let next = level.downsample().unwrap();
+
+        assert_eq!(next.width, 2);
+        assert_close(mean(&next), mean(&level));
+        assert_close(next.pixels[0], Vec3::from(0.4 * 1. + 0.4 * 2. + 0.2 * 3.));
+        assert_close(next.pixels[1], Vec3::from(0.2 * 3. + 0.4 * 4. + 0.4 * 10.));
+    }
+
+    #[test]
+    fn downsample_ends_at_one_pixel() {
+        let mut level = Level {
+            width: 7,
+            height: 3,
+            pixels: (0..21).map(|i| Vec3::from(i as f32)).collect(),
+        };
+        let mean_before = mean(&level);
+
+        while let Some(next) = level.downsample() {
+            assert_close(mean(&next), mean_before);
+            level = next;
+        }
+
+        assert_eq!((level.width, level.height), (1, 1));
+    }
+}
@@ -0,0 +1,80 @@
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+/// Frames of an animation, numbered from 1. Frame `n` starts at `(n - 1) / fps` seconds of
+/// scene time, the unit of `Ray::t`.
+#[derive(Debug, Copy, Clone)]
+pub struct Timeline {
+    pub frames: usize,
+    pub fps: f32,
+    /// Portion of each frame the shutter is open, in degrees; 360 means the whole frame.
+    pub shutter_angle: f32,
+}
+
+impl Timeline {
+    pub fn time(&self, frame: usize) -> f32 {
+        debug_assert!(frame >= 1, "frames are numbered from 1");
+        (frame - 1) as f32 / self.fps
+    }
+
+    /// Exposure interval of `frame`, opening at the start of the frame.
+    pub fn exposure(&self, frame: usize) -> Range<f32> {
+        let t = self.time(frame);
+        t..t + self.shutter_angle / 360. / self.fps
+    }
+
+    /// `path` with the zero-padded frame number appended to its stem, e.g. `out/frame.png`
+    /// becomes `out/frame_0001.png`.
+    pub fn frame_path(path: &Path, frame: usize) -> PathBuf {
+        let mut name = path.file_stem().unwrap_or_default().to_os_string();
+        name.push(format!("_{:04}", frame));
+        if let Some(ext) = path.extension() {
+            name.push(".");
+            name.push(ext);
+        }
+        path.with_file_name(name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIMELINE: Timeline = Timeline {
+        frames: 48,
+        fps: 24.,
+        shutter_angle: 180.,
+    };
+
+    #[test]
+    fn first_frame_starts_at_zero() {
+        assert_eq!(TIMELINE.time(1), 0.);
+        assert_eq!(TIMELINE.time(25), 1.);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "numbered from 1")]
+    fn frame_zero_is_rejected() {
+        TIMELINE.time(0);
+    }
+
+    #[test]
+    fn exposure_covers_shutter_angle() {
+        let exposure = TIMELINE.exposure(13);
+        assert_eq!(exposure.start, 0.5);
+        assert!((exposure.end - exposure.start - 1. / 48.).abs() < 1e-6);
+    }
+
+    #[test]
+    fn frame_path_appends_number() {
+        assert_eq!(
+            Timeline::frame_path(Path::new("out/frame.png"), 7),
+            Path::new("out/frame_0007.png")
+        );
+        assert_eq!(
+            Timeline::frame_path(Path::new("frame"), 12345),
+            Path::new("frame_12345")
+        );
+    }
+}
@@ -0,0 +1,248 @@
+use crate::color::{mat3_mul, srgb_encode, WorkingSpace};
+use crate::math::{Channel::*, Vec3, ZipMap};
+
+/// Curve that compresses linear radiance into the displayable range.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Operator {
+    /// Clips everything above one.
+    Clamp,
+    /// `L / (1 + L)` on luminance, which never reaches white.
+    Reinhard,
+    /// Reinhard scaled so that luminance `white` maps to one.
+    ExtendedReinhard,
+    /// Stephen Hill's fit of the ACES reference rendering and sRGB output transforms.
+    Aces,
+    /// Troy Sobotka's AgX with the default look, which desaturates highlights towards white
+    /// instead of skewing their hue.
+    Agx,
+}
+
+impl Operator {
+    pub const ALL: [Operator; 5] = [
+        Operator::Clamp,
+        Operator::Reinhard,
+        Operator::ExtendedReinhard,
+        Operator::Aces,
+        Operator::Agx,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Operator::Clamp => "clamp",
+            Operator::Reinhard => "reinhard",
+            Operator::ExtendedReinhard => "extended-reinhard",
+            Operator::Aces => "aces",
+            Operator::Agx => "agx",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Operator> {
+        Operator::ALL.iter().copied().find(|o| o.name() == name)
+    }
+}
+
+/// Maps linear scene radiance to 8-bit sRGB for display.
+#[derive(Debug, Copy, Clone)]
+pub struct ToneMap {
+    pub operator: Operator,
+    /// Exposure compensation in stops, applied before the operator.
+    pub exposure: f32,
+    /// Luminance mapped to white by `Operator::ExtendedReinhard`.
+    pub white: f32,
+    /// Space of the values being mapped, converted to Rec. 709 first.
+    pub working_space: WorkingSpace,
+}
+
+impl Default for ToneMap {
+    fn default() -> Self {
+        ToneMap {
+            operator: Operator::Clamp,
+            exposure: 0.,
+            white: 4.,
+            working_space: WorkingSpace::Rec709,
+        }
+    }
+}
+
+impl ToneMap {
+    /// Linear display values in [0, 1].
+    pub fn map(&self, c: Vec3) -> Vec3 {
+        let c = self.working_space.to_rec709(c).max(Vec3::from(0.)) * 2f32.powf(self.exposure);
+
+        let mapped = match self.operator {
+            Operator::Clamp => c,
+            Operator::Reinhard => scale_luminance(c, |l| l / (1. + l)),
+            Operator::ExtendedReinhard => {
+                let white_sqr = self.white * self.white;
+                scale_luminance(c, |l| l * (1. + l / white_sqr) / (1. + l))
+            }
+            Operator::Aces => aces(c),
+            Operator::Agx => agx(c),
+        };
+
+        mapped.map(|v| v.clamp(0., 1.))
+    }
+
+    /// Tone maps and sRGB encodes `c`.
+    pub fn encode(&self, c: Vec3) -> [u8; 3] {
+        let c = self.map(c).map(|v| 255. * srgb_encode(v) + 0.5);
+        [c[R] as u8, c[G] as u8, c[B] as u8]
+    }
+}
+
+/// Rec. 709 luminance of a linear color.
+pub fn luminance(c: Vec3) -> f32 {
+    0.2126 * c[R] + 0.7152 * c[G] + 0.0722 * c[B]
+}
+
+fn scale_luminance(c: Vec3, f: impl Fn(f32) -> f32) -> Vec3 {
+    let l = luminance(c);
+
+    if l <= 0. {
+        c
+    } else {
+        c * (f(l) / l)
+    }
+}
+
+fn aces(c: Vec3) -> Vec3 {
+    // sRGB to the input space of the fitted reference rendering transform, and back
+    const INPUT: [[f32; 3]; 3] = [
+        [0.59719, 0.35458, 0.04823],
+        [0.07600, 0.90834, 0.01566],
+        [0.02840, 0.13383, 0.83777],
+    ];
+    const OUTPUT: [[f32; 3]; 3] = [
+        [1.60475, -0.53108, -0.07367],
+        [-0.10208, 1.10813, -0.00605],
+        [-0.00327, -0.07276, 1.07602],
+    ];
+
+    let c = mat3_mul(&INPUT, c).map(|v| {
+        let a = v * (v + 0.024_578_6) - 0.000_090_537;
+        let b = v * (0.983_729 * v + 0.432_951) + 0.238_081;
+        a / b
+    });
+
+    mat3_mul(&OUTPUT, c)
+}
+
+fn agx(c: Vec3) -> Vec3 {
+    // Like the reference implementation, AgX is applied in Rec. 2020
+    const SRGB_TO_REC2020: [[f32; 3]; 3] = [
+        [0.6274, 0.3293, 0.0433],
+        [0.0691, 0.9195, 0.0113],
+        [0.0164, 0.0880, 0.8956],
+    ];
+    const REC2020_TO_SRGB: [[f32; 3]; 3] = [
+        [1.6605, -0.5876, -0.0728],
+        [-0.1246, 1.1329, -0.0083],
+        [-0.0182, -0.1006, 1.1187],
+    ];
+    const INSET: [[f32; 3]; 3] = [
+        [0.856_627_15, 0.095_121_24, 0.048_251_61],
+        [0.137_318_97, 0.761_242, 0.101_439_04],
+        [0.111_898_21, 0.076_799_42, 0.811_302_37],
+    ];
+    const OUTSET: [[f32; 3]; 3] = [
+        [1.127_100_6, -0.110_606_64, -0.016_493_94],
+        [-0.141_329_76, 1.157_823_7, -0.016_493_94],
+        [-0.141_329_76, -0.110_606_64, 1.251_936_4],
+    ];
+    const MIN_EV: f32 = -12.473_93;
+    const MAX_EV: f32 = 4.026_069;
+
+    let c = mat3_mul(&INSET, mat3_mul(&SRGB_TO_REC2020, c)).map(|v| {
+        let v = ((v.max(1e-10).log2() - MIN_EV) / (MAX_EV - MIN_EV)).clamp(0., 1.);
+
+        // Polynomial fit of the default sigmoid
+        let v2 = v * v;
+        let v4 = v2 * v2;
+        15.5 * v4 * v2 - 40.14 * v4 * v + 31.96 * v4 - 6.868 * v2 * v + 0.4298 * v2 + 0.1191 * v
+            - 0.00232
+    });
+
+    // The sigmoid's output is encoded with a 2.2 gamma
+    let c = mat3_mul(&OUTSET, c).map(|v| v.max(0.).powf(2.2));
+
+    mat3_mul(&REC2020_TO_SRGB, c)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tone_map(operator: Operator) -> ToneMap {
+        ToneMap {
+            operator,
+            ..ToneMap::default()
+        }
+    }
+
+    fn grey(operator: Operator, v: f32) -> f32 {
+        luminance(tone_map(operator).map(Vec3::from(v)))
+    }
+
+    #[test]
+    fn black_stays_black() {
+        for &op in &Operator::ALL {
+            assert!(grey(op, 0.) < 1e-3, "{}: {}", op.name(), grey(op, 0.));
+        }
+    }
+
+    #[test]
+    fn maps_one() {
+        assert!((grey(Operator::Clamp, 1.) - 1.).abs() < 1e-6);
+        assert!((grey(Operator::Reinhard, 1.) - 0.5).abs() < 1e-6);
+
+        // 1 · (1 + 1/16) / 2 with the default white of 4
+        let extended = grey(Operator::ExtendedReinhard, 1.);
+        assert!((extended - 0.531_25).abs() < 1e-6, "{}", extended);
+
+        // The filmic curves keep mid-tones below white and leave headroom above one
+        for &op in &[Operator::Aces, Operator::Agx] {
+            let v = grey(op, 1.);
+            assert!(v > 0.5 && v < 0.95, "{}: {}", op.name(), v);
+        }
+    }
+
+    #[test]
+    fn maps_white_point_to_one() {
+        for &white in &[1., 4., 11.2] {
+            let tone_map = ToneMap {
+                white,
+                ..tone_map(Operator::ExtendedReinhard)
+            };
+            let v = luminance(tone_map.map(Vec3::from(white)));
+            assert!((v - 1.).abs() < 1e-5, "{}: {}", white, v);
+        }
+
+        // Reinhard never reaches white, the filmic curves saturate towards it
+        assert!(grey(Operator::Reinhard, 1e3) < 1.);
+        for &op in &[Operator::Aces, Operator::Agx] {
+            assert!(grey(op, 64.) > 0.97, "{}: {}", op.name(), grey(op, 64.));
+        }
+    }
+
+    #[test]
+    fn operators_are_monotonic() {
+        for &op in &Operator::ALL {
+            let mut prev = grey(op, 0.);
+
+            for i in 1..=400 {
+                let v = grey(op, 2f32.powf(i as f32 / 20. - 10.));
+                assert!(v >= prev - 1e-6, "{}: {} after {}", op.name(), v, prev);
+                prev = v;
+            }
+        }
+    }
+
+    #[test]
+    fn exposure_is_in_stops() {
+        let tone_map = ToneMap {
+            exposure: 2.,
+            ..tone_map(Operator::Clamp)
+        };
+        assert!((luminance(tone_map.map(Vec3::from(0.125))) - 0.5).abs() < 1e-6);
+    }
+}
@@ -1,49 +1,255 @@
 use crate::bvh::Bvh;
-use crate::math::Vec3;
+use crate::light::Light;
+use crate::material::Material;
+use crate::math::{Fold, Vec3};
 use crate::primitive::{HitRecord, Hittable};
 use crate::ray::Ray;
-use crate::{EPSILON, MAX_BOUNCES};
+use crate::EPSILON;
 use rand::Rng;
 use std::sync::Arc;
 
 pub trait World: Send + Sync {
     fn trace(&self, ray: &Ray, rng: &mut dyn FnMut() -> f32) -> Option<HitRecord>;
+
+    /// Emissive geometry for light sampling.
+    fn lights(&self) -> &[Light] {
+        &[]
+    }
 }
 
 pub type Background = Box<dyn Fn(Vec3) -> Vec3 + Sync + Send>;
 
+/// Limits on path length. Bounce counts exclude the camera ray.
+#[derive(Debug, Copy, Clone)]
+pub struct PathConfig {
+    /// Bounces before Russian roulette may terminate a path.
+    pub min_depth: usize,
+    pub max_depth: usize,
+    pub max_diffuse: usize,
+    /// Reflections and refractions off metals and dielectrics.
+    pub max_specular: usize,
+    /// Scattering events inside participating media.
+    pub max_volume: usize,
+}
+
+impl Default for PathConfig {
+    fn default() -> Self {
+        PathConfig {
+            min_depth: 3,
+            max_depth: 64,
+            max_diffuse: 16,
+            max_specular: 64,
+            max_volume: 64,
+        }
+    }
+}
+
+/// Surface attributes at the first intersection of a camera ray, used for the auxiliary
+/// render passes.
+#[derive(Copy, Clone)]
+pub struct FirstHit {
+    pub albedo: Vec3,
+    pub normal: Vec3,
+    /// Distance from the ray origin, infinite if nothing was hit.
+    pub depth: f32,
+    pub position: Vec3,
+    pub uv: Vec3,
+    /// See `primitive::Tagged`, zero for background and untagged objects.
+    pub id: u32,
+    /// See `primitive::MaterialTagged`, zero for background and untagged materials.
+    pub material_id: u32,
+}
+
+impl FirstHit {
+    fn miss(albedo: Vec3) -> Self {
+        FirstHit {
+            albedo,
+            normal: Vec3::from(0.),
+            depth: f32::INFINITY,
+            position: Vec3::from(0.),
+            uv: Vec3::from(0.),
+            id: 0,
+            material_id: 0,
+        }
+    }
+
+    fn new(ray: &Ray, hit: &HitRecord) -> Self {
+        FirstHit {
+            albedo: hit.material().albedo(hit),
+            normal: hit.normal(),
+            depth: hit.t() * ray.dir.len(),
+            position: hit.point(),
+            uv: hit.uv(),
+            id: hit.object_id(),
+            material_id: hit.material_id(),
+        }
+    }
+}
+
 pub fn ray_color(
-    mut ray: Ray,
+    ray: Ray,
     world: &impl World,
     background: &Background,
+    config: &PathConfig,
     rng: &mut impl Rng,
 ) -> Vec3 {
-    let mut bounces = 0;
+    trace_path(ray, world, background, config, rng).0
+}
+
+/// Like `ray_color`, but also returns the attributes of the first intersection.
+///
+/// Emissive geometry is sampled directly at every non-specular bounce and combined with
+/// the scattered rays that hit it through multiple importance sampling. Past
+/// `config.min_depth` bounces paths are terminated by Russian roulette on their throughput.
+pub fn trace_path(
+    mut ray: Ray,
+    world: &impl World,
+    background: &Background,
+    config: &PathConfig,
+    rng: &mut impl Rng,
+) -> (Vec3, FirstHit) {
+    let lights = world.lights();
+    let (mut bounces, mut diffuse, mut specular, mut volume) = (0, 0, 0, 0);
     let mut acc = Vec3::from(0.);
     let mut strength = Vec3::from(1.);
+    let mut first = None;
+    // Density of the current ray's direction, none for camera rays and specular bounces
+    let mut scattering_pdf: Option<f32> = None;
+
+    while let Some(mut hit) = world.trace(&ray, &mut || rng.gen()) {
+        hit.compute_differentials(&ray);
+
+        if first.is_none() {
+            first = Some(FirstHit::new(&ray, &hit));
+        }
+
+        let material = hit.material();
 
-    while let Some(hit) = world.trace(&ray, &mut || rng.gen()) {
-        acc = acc + strength * hit.material().emitted(&hit);
+        let weight = match scattering_pdf {
+            Some(pdf) if material.is_emissive() => power_heuristic(pdf, light_pdf(lights, &ray)),
+            _ => 1.,
+        };
+        acc = acc + weight * strength * material.emitted(&hit);
+
+        let (count, max) = match material {
+            Material::Isotropic { .. } => (&mut volume, config.max_volume),
+            _ if material.is_specular() => (&mut specular, config.max_specular),
+            _ => (&mut diffuse, config.max_diffuse),
+        };
+
+        // Checked before sampling the lights, whose paths are one bounce longer and
+        // weighted against scattered rays that wouldn't be traced
+        if *count >= max || bounces >= config.max_depth {
+            return (acc, first.unwrap());
+        }
+
+        if !material.is_specular() && !lights.is_empty() {
+            acc = acc + strength * sample_light(lights, &ray, &hit, world, rng);
+        }
 
-        if let Some((attenuation, r)) = hit.material().scatter(&ray, &hit, rng) {
-            strength = strength * attenuation;
-            ray = r;
+        if let Some(sample) = material.sample(-ray.dir.unit(), &hit, rng) {
+            scattering_pdf = if sample.specular {
+                None
+            } else {
+                Some(sample.pdf)
+            };
+            strength = strength * sample.weight;
+            ray = material.scattered_ray(&ray, &hit, &sample);
+
+            *count += 1;
+            bounces += 1;
         } else {
             break;
         }
 
-        bounces += 1;
-        if bounces > MAX_BOUNCES {
-            return acc;
+        if bounces > config.min_depth {
+            let survival = strength.fold(0., f32::max).min(0.95);
+
+            if rng.gen::<f32>() >= survival {
+                return (acc, first.unwrap());
+            }
+
+            strength = strength / survival;
         }
     }
-    acc + strength * background(ray.dir.unit())
+
+    let bg = background(ray.dir.unit());
+    (
+        acc + strength * bg,
+        first.unwrap_or_else(|| FirstHit::miss(bg)),
+    )
+}
+
+/// Radiance reflected at `hit` from a point sampled on a random light.
+fn sample_light(
+    lights: &[Light],
+    ray: &Ray,
+    hit: &HitRecord,
+    world: &impl World,
+    rng: &mut impl Rng,
+) -> Vec3 {
+    let time = ray.t;
+    let light = &lights[rng.gen_range(0..lights.len())];
+
+    let sample = match light.sample(hit.point(), time, rng) {
+        Some(s) => s,
+        None => return Vec3::from(0.),
+    };
+
+    let material = hit.material();
+    let wo = -ray.dir.unit();
+    let f = material.eval(sample.dir, wo, hit);
+    let scattering_pdf = material.pdf(sample.dir, wo, hit);
+
+    if scattering_pdf <= 0. {
+        return Vec3::from(0.);
+    }
+
+    let shadow_ray = Ray {
+        origin: hit.point(),
+        dir: sample.dir,
+        t: time,
+        differential: None,
+    };
+
+    if let Some(h) = world.trace(&shadow_ray, &mut || rng.gen()) {
+        if h.t() < sample.distance - EPSILON {
+            return Vec3::from(0.);
+        }
+    }
+
+    let light_pdf = light_pdf(lights, &shadow_ray);
+
+    if light_pdf <= 0. {
+        return Vec3::from(0.);
+    }
+
+    power_heuristic(light_pdf, scattering_pdf) / light_pdf * f * sample.emitted
+}
+
+/// Density of `ray`'s direction when sampling a uniformly chosen light.
+fn light_pdf(lights: &[Light], ray: &Ray) -> f32 {
+    if lights.is_empty() {
+        return 0.;
+    }
+
+    lights.iter().map(|l| l.pdf(ray)).sum::<f32>() / lights.len() as f32
+}
+
+#[inline]
+fn power_heuristic(pdf: f32, other: f32) -> f32 {
+    let (a, b) = (pdf * pdf, other * other);
+    a / (a + b)
 }
 
 impl World for Arc<dyn World> {
     fn trace(&self, ray: &Ray, rng: &mut dyn FnMut() -> f32) -> Option<HitRecord> {
         (**self).trace(ray, rng)
     }
+
+    fn lights(&self) -> &[Light] {
+        (**self).lights()
+    }
 }
 
 impl World for &[Arc<dyn Hittable>] {
@@ -68,4 +274,122 @@ impl World for Bvh {
     fn trace(&self, ray: &Ray, rng: &mut dyn FnMut() -> f32) -> Option<HitRecord> {
         self.hit(ray, EPSILON..f32::INFINITY, rng)
     }
+
+    fn lights(&self) -> &[Light] {
+        &self.lights
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::math::Channel::R;
+    use crate::primitive::Sphere;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    const ALBEDO: f32 = 0.5;
+    /// Squared ratio of the light's radius to the shell's.
+    const K: f32 = 0.25;
+
+    /// Unit radius light inside a diffuse shell of radius 2.
+    ///
+    /// A cosine-distributed ray leaving the shell sees the light with probability `K` and
+    /// the shell otherwise, so the shell's radiance `L` satisfies `L = a (K + (1 - K) L)`.
+    fn furnace() -> Bvh {
+        let objs: Vec<Box<dyn Hittable>> = vec![
+            Box::new(Sphere {
+                center: Vec3::from(0.),
+                radius: 1.,
+                material: Material::DiffuseLight {
+                    emit: Vec3::from(1.).into(),
+                    intensity: 1.,
+                },
+            }),
+            Box::new(Sphere {
+                center: Vec3::from(0.),
+                radius: 2.,
+                material: Material::Lambertian {
+                    albedo: Vec3::from(ALBEDO).into(),
+                },
+            }),
+        ];
+
+        Bvh::new(objs, 0.0..0.0)
+    }
+
+    /// Mean radiance of a ray from between the spheres towards the shell.
+    fn mean(config: &PathConfig, seed: u64) -> f32 {
+        let world = furnace();
+        let background: Background = Box::new(|_| Vec3::from(0.));
+        let mut rng = StdRng::seed_from_u64(seed);
+
+        let n = 20_000;
+        let sum: f32 = (0..n)
+            .map(|_| {
+                let ray = Ray {
+                    origin: Vec3::new(0., 0., 1.5),
+                    dir: Vec3::new(0., 0., 1.),
+                    t: 0.,
+                    differential: None,
+                };
+                ray_color(ray, &world, &background, config, &mut rng)[R]
+            })
+            .sum();
+
+        sum / n as f32
+    }
+
+    #[test]
+    fn furnace_converges_with_russian_roulette() {
+        let expected = ALBEDO * K / (1. - ALBEDO * (1. - K));
+
+        for &min_depth in &[0, 3, 64] {
+            let config = PathConfig {
+                min_depth,
+                ..PathConfig::default()
+            };
+            let mean = mean(&config, 0);
+
+            assert!(
+                (mean - expected).abs() < 0.02 * expected,
+                "{}: {} != {}",
+                min_depth,
+                mean,
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn depth_limits_end_paths_before_sampling_lights() {
+        // One bounce off the shell: light reached by either strategy, with nothing more
+        let one_bounce = ALBEDO * K;
+
+        let configs = [
+            PathConfig {
+                max_depth: 1,
+                ..PathConfig::default()
+            },
+            PathConfig {
+                max_diffuse: 1,
+                ..PathConfig::default()
+            },
+        ];
+        for config in &configs {
+            let mean = mean(config, 1);
+            assert!(
+                (mean - one_bounce).abs() < 0.02 * one_bounce,
+                "{:?}: {}",
+                config,
+                mean
+            );
+        }
+
+        let config = PathConfig {
+            max_depth: 0,
+            ..PathConfig::default()
+        };
+        assert_eq!(mean(&config, 2), 0.);
+    }
 }